@@ -0,0 +1,138 @@
+use crate::options::Source;
+use duckdb::{params_from_iter, Connection};
+use log2duck::LogEntry;
+use std::collections::HashMap;
+
+/** Dimensions that can be used to group the requests with --group-by */
+pub const DIMENSIONS: [&str; 15] = [
+    "hour",
+    "day",
+    "status_code",
+    "method",
+    "path",
+    "route",
+    "section",
+    "extension",
+    "country",
+    "continent",
+    "browser",
+    "os",
+    "device",
+    "vhost",
+    "source",
+];
+
+/** Count the requests and bytes by some dimensions, without storing the entries */
+pub struct Aggregator {
+    dimensions: Vec<String>,
+    groups: HashMap<Vec<Option<String>>, (usize, usize)>,
+}
+
+impl Aggregator {
+    pub fn new(dimensions: Vec<String>) -> Aggregator {
+        Aggregator {
+            dimensions,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, log: &LogEntry, source: &Source) {
+        let key = self
+            .dimensions
+            .iter()
+            .map(|dimension| value(dimension, log, source))
+            .collect();
+        let (requests, bytes) = self.groups.entry(key).or_insert((0, 0));
+        *requests += 1;
+        *bytes += log.size;
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /**
+     * Create the aggregates table (or check that the existing one has the same dimensions)
+     * and insert the counts. The counts of previous imports are kept, so they must be summed.
+     */
+    pub fn save(&self, conn: &mut Connection) -> Result<(), String> {
+        let columns: Vec<String> = self
+            .dimensions
+            .iter()
+            .map(|dimension| format!("{} {}", dimension, column_type(dimension)))
+            .collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS aggregates ({}, requests UBIGINT NOT NULL, bytes UBIGINT NOT NULL)",
+            columns.join(", ")
+        ))
+        .map_err(|err| err.to_string())?;
+
+        let mut stmt = conn
+            .prepare("SELECT column_name FROM duckdb_columns() WHERE table_name = 'aggregates'")
+            .map_err(|err| err.to_string())?;
+        let existing: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|err| err.to_string())?;
+        let expected: Vec<&str> = self
+            .dimensions
+            .iter()
+            .map(|dimension| dimension.as_str())
+            .chain(["requests", "bytes"])
+            .collect();
+        if existing != expected {
+            return Err(format!(
+                "The aggregates table has the columns ({}), but ({}) were expected",
+                existing.join(", "),
+                expected.join(", ")
+            ));
+        }
+
+        let placeholders = vec!["?"; self.dimensions.len() + 2].join(", ");
+        let sql = format!("INSERT INTO aggregates VALUES ({})", placeholders);
+        let tx = conn.transaction().map_err(|err| err.to_string())?;
+        {
+            let mut stmt = tx.prepare(&sql).map_err(|err| err.to_string())?;
+            for (key, (requests, bytes)) in &self.groups {
+                let values = key
+                    .iter()
+                    .cloned()
+                    .chain([Some(requests.to_string()), Some(bytes.to_string())]);
+                stmt.execute(params_from_iter(values))
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        tx.commit().map_err(|err| err.to_string())
+    }
+}
+
+/** Value of a dimension of the entry, DuckDB casts it to the type of the column */
+fn value(dimension: &str, log: &LogEntry, source: &Source) -> Option<String> {
+    match dimension {
+        "hour" => Some(log.timestamp.format("%Y-%m-%d %H:00:00").to_string()),
+        "day" => Some(log.timestamp.format("%Y-%m-%d").to_string()),
+        "status_code" => Some(log.status_code.to_string()),
+        "method" => Some(log.method.to_string().to_string()),
+        "path" => Some(log.path.clone()),
+        "route" => log.route.clone(),
+        "section" => Some(log.section.clone()),
+        "extension" => log.extension.clone(),
+        "country" => log.country.clone(),
+        "continent" => log.continent.clone(),
+        "browser" => log.browser.clone(),
+        "os" => log.os.clone(),
+        "device" => log.device.clone(),
+        "vhost" => log.vhost.clone(),
+        "source" => source.label.clone(),
+        _ => None,
+    }
+}
+
+fn column_type(dimension: &str) -> &str {
+    match dimension {
+        "hour" => "TIMESTAMP",
+        "day" => "DATE",
+        "status_code" => "USMALLINT",
+        _ => "VARCHAR",
+    }
+}
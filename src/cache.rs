@@ -0,0 +1,163 @@
+use crate::options::Options;
+use duckdb::{params, Connection};
+use log2duck::{Agent, GeoLocation, LookupCache, MemoryCache};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+// Memory used by DuckDB for the disk cache, the rest of the values stay in the file
+const DISK_CACHE_MEMORY_LIMIT: &str = "256MB";
+
+/** Cache of user agents and IPs of an import, shared by the services of all the workers */
+pub fn new(options: &Options) -> Result<Arc<dyn LookupCache>, String> {
+    if options.disk_cache {
+        Ok(Arc::new(DiskCache::new()?))
+    } else {
+        Ok(Arc::new(MemoryCache::default()))
+    }
+}
+
+/**
+ * Cache of user agents and IPs stored in a temporary DuckDB database (used by --cache-backend disk),
+ * so the memory used doesn't grow with the number of different values.
+ * Every lookup is a query, so it's slower than the memory cache.
+ * The database is removed when the cache is dropped.
+ */
+pub struct DiskCache {
+    conn: Mutex<Option<Connection>>,
+    filename: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new() -> Result<DiskCache, String> {
+        let filename = env::temp_dir().join(format!("log2duck-cache-{}.db", process::id()));
+
+        // A file left by a killed process with the same id
+        remove_database(&filename);
+
+        let conn = Connection::open(&filename)
+            .and_then(|conn| {
+                conn.execute_batch(&format!(
+                    "SET memory_limit = '{}';
+                    CREATE TABLE agents (key UBIGINT PRIMARY KEY, user_agent VARCHAR, agent VARCHAR);
+                    CREATE TABLE geolocations (ip VARCHAR PRIMARY KEY, geolocation VARCHAR);",
+                    DISK_CACHE_MEMORY_LIMIT
+                ))?;
+                Ok(conn)
+            })
+            .map_err(|err| {
+                remove_database(&filename);
+                format!(
+                    "Unable to create the disk cache {} ({})",
+                    filename.display(),
+                    err
+                )
+            })?;
+
+        Ok(DiskCache {
+            conn: Mutex::new(Some(conn)),
+            filename,
+        })
+    }
+}
+
+// The errors are ignored: a value that can't be read or saved is looked up again
+impl LookupCache for DiskCache {
+    fn get_agent(&self, user_agent: &str) -> Option<Arc<Agent>> {
+        let conn = self.conn.lock().unwrap();
+        let (stored, agent): (String, String) = conn
+            .as_ref()?
+            .prepare_cached("SELECT user_agent, agent FROM agents WHERE key = ?")
+            .ok()?
+            .query_row(params![agent_key(user_agent)], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .ok()?;
+
+        // Other user agent with the same hash
+        if stored != user_agent {
+            return None;
+        }
+
+        serde_json::from_str(&agent).ok().map(Arc::new)
+    }
+
+    fn set_agent(&self, user_agent: &str, agent: Arc<Agent>) {
+        let Ok(agent) = serde_json::to_string(&*agent) else {
+            return;
+        };
+        let conn = self.conn.lock().unwrap();
+        if let Some(conn) = conn.as_ref() {
+            let _ = conn
+                .prepare_cached("INSERT OR IGNORE INTO agents VALUES (?, ?, ?)")
+                .and_then(|mut statement| {
+                    statement.execute(params![agent_key(user_agent), user_agent, agent])
+                });
+        }
+    }
+
+    fn get_geolocation(&self, ip: &IpAddr) -> Option<Arc<GeoLocation>> {
+        let conn = self.conn.lock().unwrap();
+        let geolocation: String = conn
+            .as_ref()?
+            .prepare_cached("SELECT geolocation FROM geolocations WHERE ip = ?")
+            .ok()?
+            .query_row(params![ip.to_string()], |row| row.get(0))
+            .ok()?;
+
+        serde_json::from_str(&geolocation).ok().map(Arc::new)
+    }
+
+    fn set_geolocation(&self, ip: &IpAddr, geolocation: Arc<GeoLocation>) {
+        let Ok(geolocation) = serde_json::to_string(&*geolocation) else {
+            return;
+        };
+        let conn = self.conn.lock().unwrap();
+        if let Some(conn) = conn.as_ref() {
+            let _ = conn
+                .prepare_cached("INSERT OR IGNORE INTO geolocations VALUES (?, ?)")
+                .and_then(|mut statement| statement.execute(params![ip.to_string(), geolocation]));
+        }
+    }
+
+    fn clear_agents(&self) {
+        if let Some(conn) = self.conn.lock().unwrap().as_ref() {
+            let _ = conn.execute_batch("DELETE FROM agents");
+        }
+    }
+
+    fn clear_geolocations(&self) {
+        if let Some(conn) = self.conn.lock().unwrap().as_ref() {
+            let _ = conn.execute_batch("DELETE FROM geolocations");
+        }
+    }
+}
+
+impl Drop for DiskCache {
+    fn drop(&mut self) {
+        // The connection is closed before removing the files
+        if let Ok(conn) = self.conn.get_mut() {
+            conn.take();
+        }
+        remove_database(&self.filename);
+    }
+}
+
+/**
+ * The user agents are stored by a hash of 64 bits instead of the whole string,
+ * so the index of the table is small.
+ */
+fn agent_key(user_agent: &str) -> u64 {
+    let hash = blake3::hash(user_agent.as_bytes());
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+/** Remove a database with its write-ahead log and the directory of the data spilled by DuckDB */
+fn remove_database(filename: &Path) {
+    let _ = fs::remove_file(filename);
+    let _ = fs::remove_file(filename.with_extension("db.wal"));
+    let _ = fs::remove_dir_all(filename.with_extension("db.tmp"));
+}
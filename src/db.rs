@@ -0,0 +1,654 @@
+use crate::options::Source;
+use chrono::{DateTime, NaiveDate, Utc};
+use duckdb::{params, Connection};
+use log2duck::{GeoLocation, HttpMethod, HttpVersion, LineFormat, LogError, RawEntry};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Version of the schema of the log table, increase it on incompatible changes
+const SCHEMA_VERSION: i32 = 8;
+
+// Maximum time between two attempts to open a locked database
+const MAX_LOCK_DELAY: Duration = Duration::from_secs(5);
+
+const LOG_TABLE: &str = r"
+    CREATE TABLE log (
+        source               VARCHAR,
+        source_file          VARCHAR,
+        request_id           VARCHAR,
+        trace_id             VARCHAR,
+        vhost                VARCHAR,
+        vhost_port           USMALLINT,
+        server_port          USMALLINT,
+        ip                   VARCHAR,
+        x_forwarded_for      VARCHAR,
+        visitor_id           VARCHAR,
+        session_id           VARCHAR,
+        identity             VARCHAR,
+        user                 VARCHAR,
+        timestamp            TIMESTAMP,
+        tz_offset_seconds    INTEGER,
+        date                 DATE,
+        hour                 USMALLINT,
+        weekday              USMALLINT,
+        method               METHOD,
+        scheme               VARCHAR,
+        request_target       VARCHAR,
+        path                 VARCHAR,
+        route                VARCHAR,
+        section              VARCHAR,
+        extension            VARCHAR,
+        is_asset             BOOLEAN,
+        query                VARCHAR,
+        canonical_query      VARCHAR,
+        fragment             VARCHAR,
+        http_version         HTTP_VERSION,
+        status_code          USMALLINT,
+        size                 UINTEGER,
+        request_length       UBIGINT,
+        bytes_sent           UBIGINT,
+        request_time         DOUBLE,
+        upstream_time        DOUBLE,
+        upstream_addr        VARCHAR,
+        cache_status         VARCHAR,
+        content_type         VARCHAR,
+        connection_status    VARCHAR,
+        ssl_protocol         VARCHAR,
+        ssl_cipher           VARCHAR,
+        referer              VARCHAR,
+        referer_origin       VARCHAR,
+        referer_path         VARCHAR,
+        referer_has_path     BOOLEAN,
+        referer_query        VARCHAR,
+        user_agent           VARCHAR,
+        browser              VARCHAR,
+        browser_major        USMALLINT,
+        browser_minor        USMALLINT,
+        browser_patch        USMALLINT,
+        browser_patch_minor  USMALLINT,
+        os                   VARCHAR,
+        os_major             USMALLINT,
+        os_minor             USMALLINT,
+        os_patch             USMALLINT,
+        os_patch_minor       USMALLINT,
+        device               VARCHAR,
+        brand                VARCHAR,
+        model                VARCHAR,
+        ua_canonical         VARCHAR,
+        country              VARCHAR,
+        country_code         VARCHAR,
+        continent            VARCHAR,
+        continent_code       VARCHAR,
+        asn                  VARCHAR,
+        as_name              VARCHAR,
+        as_domain            VARCHAR,
+        geo_raw              JSON,
+        ptr_hostname         VARCHAR,
+        extra                JSON,
+        raw_line             VARCHAR,
+        parse_error          VARCHAR,
+    );
+";
+
+// Exact time of the last request imported of every source and virtual host, because
+// the timestamps of the log table may be truncated (--time-bucket)
+const CURSORS_TABLE: &str = r"
+    CREATE TABLE IF NOT EXISTS _log2duck_cursors (
+        source               VARCHAR,
+        vhost                VARCHAR,
+        timestamp            TIMESTAMP NOT NULL,
+    );
+";
+
+const META_TABLE: &str = r"
+    CREATE TABLE IF NOT EXISTS _log2duck_meta (
+        tool_version         VARCHAR NOT NULL,
+        schema_version       INTEGER NOT NULL,
+        origin               VARCHAR NOT NULL,
+        format               VARCHAR NOT NULL,
+        started_at           TIMESTAMP NOT NULL,
+        finished_at          TIMESTAMP NOT NULL,
+        inserted             UBIGINT NOT NULL,
+    );
+";
+
+/**
+ * Open the database, retrying with an exponential backoff while other process
+ * (like an overlapping import) holds the lock of the file.
+ */
+pub fn open(filename: &str, timeout: Duration) -> Result<Connection, String> {
+    let started = Instant::now();
+    let mut delay = Duration::from_millis(100);
+
+    loop {
+        let error = match Connection::open(filename) {
+            Ok(conn) => return Ok(conn),
+            Err(err) => err.to_string(),
+        };
+
+        // DuckDB fails with "Could not set lock on file" if it's used by other process
+        if !error.contains("lock") {
+            return Err(error);
+        }
+
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(format!(
+                "The database {} is locked by other process, gave up after {:.1}s",
+                filename,
+                timeout.as_secs_f64()
+            ));
+        }
+
+        thread::sleep(delay.min(remaining));
+        delay = (delay * 2).min(MAX_LOCK_DELAY);
+    }
+}
+
+/**
+ * Create the types and tables, or check that the existing ones are compatible.
+ * With auto_migrate, an incompatible log table is removed and created again.
+ */
+pub fn setup(conn: &Connection, auto_migrate: bool) -> Result<(), String> {
+    let methods: Vec<&str> = HttpMethod::ALL.iter().map(|m| m.to_string()).collect();
+    let versions: Vec<&str> = HttpVersion::ALL.iter().map(|v| v.to_string()).collect();
+
+    if table_exists(conn, "log")? {
+        let compatible = check_schema_version(conn)
+            .and_then(|_| check_enum(conn, "METHOD", &methods))
+            .and_then(|_| check_enum(conn, "HTTP_VERSION", &versions))
+            .and_then(|_| check_columns(conn));
+
+        match compatible {
+            Ok(_) => {
+                return conn
+                    .execute_batch(&format!("{}\n{}", META_TABLE, CURSORS_TABLE))
+                    .map_err(|err| err.to_string())
+            }
+            Err(error) if !auto_migrate => {
+                return Err(format!("{} (use --auto-migrate to rebuild it)", error))
+            }
+            Err(error) => {
+                println!("Warning: {}", error);
+                println!("Rebuilding the log table, the previous rows are removed...");
+                conn.execute_batch(
+                    "DROP TABLE log; DROP TABLE IF EXISTS _log2duck_cursors; DROP TYPE IF EXISTS METHOD; DROP TYPE IF EXISTS HTTP_VERSION;",
+                )
+                .map_err(|err| err.to_string())?;
+            }
+        }
+    }
+
+    let sql = format!(
+        "CREATE TYPE METHOD AS ENUM ({});\nCREATE TYPE HTTP_VERSION AS ENUM ({});\n{}\n{}\n{}",
+        enum_values(&methods),
+        enum_values(&versions),
+        LOG_TABLE,
+        META_TABLE,
+        CURSORS_TABLE
+    );
+
+    conn.execute_batch(&sql).map_err(|err| err.to_string())
+}
+
+/**
+ * Insert a line that couldn't be parsed, with the error and the raw values
+ * of the fields that could be split. The rest of the columns are NULL.
+ */
+pub fn insert_invalid(
+    conn: &Connection,
+    source: &Source,
+    error: &LogError,
+    format: &LineFormat,
+) -> Result<(), String> {
+    let raw = RawEntry::parse(error.line(), format).ok();
+
+    conn.execute(
+        "INSERT INTO log (source, source_file, vhost, ip, identity, user, request_target, user_agent, raw_line, parse_error)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            source.label,
+            source.input,
+            field(raw.as_ref().and_then(|raw| raw.vhost)),
+            field(raw.as_ref().map(|raw| raw.ip)),
+            field(raw.as_ref().map(|raw| raw.identity)),
+            field(raw.as_ref().map(|raw| raw.user)),
+            field(raw.as_ref().map(|raw| raw.target)),
+            field(raw.as_ref().map(|raw| raw.user_agent)),
+            error.line(),
+            error.reason(),
+        ],
+    )
+    .map(|_| ())
+    .map_err(|err| err.to_string())
+}
+
+/** Empty and "-" values of the log are NULL */
+fn field(value: Option<&str>) -> Option<&str> {
+    value.filter(|value| !value.is_empty() && *value != "-")
+}
+
+/** An import, saved in the _log2duck_meta table */
+pub struct Import<'a> {
+    pub origin: &'a str,
+    pub format: &'a str,
+    pub started_at: DateTime<Utc>,
+    pub inserted: usize,
+}
+
+/** Save the import in the _log2duck_meta table, with the versions of the tool and the schema */
+pub fn save_import(conn: &Connection, import: &Import) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO _log2duck_meta VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            env!("CARGO_PKG_VERSION"),
+            SCHEMA_VERSION,
+            import.origin,
+            import.format,
+            timestamp(&import.started_at),
+            timestamp(&Utc::now()),
+            import.inserted,
+        ],
+    )
+    .map(|_| ())
+    .map_err(|err| err.to_string())
+}
+
+fn timestamp(datetime: &DateTime<Utc>) -> String {
+    datetime.format("%Y-%m-%d %H:%M:%S%.6f").to_string()
+}
+
+/**
+ * Refuse to import into a database with a different schema version.
+ * The databases created before the _log2duck_meta table existed are incompatible too.
+ */
+fn check_schema_version(conn: &Connection) -> Result<(), String> {
+    if !table_exists(conn, "_log2duck_meta")? {
+        return Err(String::from(
+            "The database was created by an older version of log2duck, without schema version",
+        ));
+    }
+
+    let version: Option<i32> = conn
+        .query_row(
+            "SELECT last(schema_version ORDER BY finished_at) FROM _log2duck_meta",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|err| err.to_string())?;
+
+    match version {
+        Some(version) if version != SCHEMA_VERSION => Err(format!(
+            "The schema version of the database is {}, but this version of log2duck uses {}",
+            version, SCHEMA_VERSION
+        )),
+        _ => Ok(()),
+    }
+}
+
+/** Create the table to store the query parameters of the requests, one per row */
+pub fn create_query_params(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS query_params (
+            request_id VARCHAR NOT NULL,
+            key VARCHAR NOT NULL,
+            value VARCHAR NOT NULL
+        );",
+    )
+    .map_err(|err| err.to_string())
+}
+
+/** Create the table to store the lines that couldn't be imported, with the reason */
+pub fn create_parse_errors(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS parse_errors (
+            source VARCHAR,
+            source_file VARCHAR NOT NULL,
+            line_number UBIGINT NOT NULL,
+            reason VARCHAR NOT NULL,
+            raw VARCHAR NOT NULL
+        );",
+    )
+    .map_err(|err| err.to_string())
+}
+
+/**
+ * Add the requests of the import to the path_counts table (the number of requests by path and day).
+ * The first time, the table is filled with all the rows of the log table, including the new ones.
+ */
+pub fn update_path_counts(
+    conn: &mut Connection,
+    counts: &HashMap<(String, NaiveDate), usize>,
+) -> Result<(), String> {
+    let tx = conn.transaction().map_err(|err| err.to_string())?;
+
+    if !table_exists(&tx, "path_counts")? {
+        tx.execute_batch(
+            "CREATE TABLE path_counts (
+                path VARCHAR NOT NULL,
+                day DATE NOT NULL,
+                count UBIGINT NOT NULL,
+                PRIMARY KEY (path, day)
+            );
+            INSERT INTO path_counts
+                SELECT path, timestamp::DATE, count(*) FROM log
+                WHERE path IS NOT NULL AND timestamp IS NOT NULL
+                GROUP BY ALL;",
+        )
+        .map_err(|err| err.to_string())?;
+    } else {
+        let mut stmt = tx
+            .prepare(
+                "INSERT INTO path_counts VALUES (?, ?, ?)
+                ON CONFLICT (path, day) DO UPDATE SET count = count + excluded.count",
+            )
+            .map_err(|err| err.to_string())?;
+
+        for ((path, day), count) in counts {
+            stmt.execute(params![path, day.to_string(), count])
+                .map_err(|err| err.to_string())?;
+        }
+    }
+
+    tx.commit().map_err(|err| err.to_string())
+}
+
+/** Different IPs of the requests of the log table */
+pub fn ips(conn: &Connection) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare("SELECT DISTINCT ip FROM log WHERE ip IS NOT NULL AND parse_error IS NULL")
+        .map_err(|err| err.to_string())?;
+    let ips = stmt
+        .query_map([], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    Ok(ips)
+}
+
+/**
+ * Replace the geolocation columns of the rows of these IPs, joining the log table
+ * with a temporary table of the new values. Returns the number of rows changed.
+ */
+pub fn update_geolocations(
+    conn: &mut Connection,
+    geolocations: &[(String, GeoLocation)],
+) -> Result<usize, String> {
+    let tx = conn.transaction().map_err(|err| err.to_string())?;
+
+    tx.execute_batch(
+        "CREATE TEMP TABLE geolocations (
+            ip VARCHAR NOT NULL,
+            country VARCHAR,
+            country_code VARCHAR,
+            continent VARCHAR,
+            continent_code VARCHAR,
+            asn VARCHAR,
+            as_name VARCHAR,
+            as_domain VARCHAR
+        );",
+    )
+    .map_err(|err| err.to_string())?;
+
+    let mut stmt = tx
+        .prepare("INSERT INTO geolocations VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+        .map_err(|err| err.to_string())?;
+    for (ip, geo) in geolocations {
+        stmt.execute(params![
+            ip,
+            geo.country,
+            geo.country_code,
+            geo.continent,
+            geo.continent_code,
+            geo.asn,
+            geo.as_name,
+            geo.as_domain,
+        ])
+        .map_err(|err| err.to_string())?;
+    }
+
+    let changed = tx
+        .execute(
+            "UPDATE log SET
+                country = geo.country,
+                country_code = geo.country_code,
+                continent = geo.continent,
+                continent_code = geo.continent_code,
+                asn = geo.asn,
+                as_name = geo.as_name,
+                as_domain = geo.as_domain
+            FROM geolocations AS geo
+            WHERE log.ip = geo.ip AND log.parse_error IS NULL AND (
+                log.country IS DISTINCT FROM geo.country
+                OR log.country_code IS DISTINCT FROM geo.country_code
+                OR log.continent IS DISTINCT FROM geo.continent
+                OR log.continent_code IS DISTINCT FROM geo.continent_code
+                OR log.asn IS DISTINCT FROM geo.asn
+                OR log.as_name IS DISTINCT FROM geo.as_name
+                OR log.as_domain IS DISTINCT FROM geo.as_domain
+            )",
+            [],
+        )
+        .map_err(|err| err.to_string())?;
+
+    tx.execute_batch("DROP TABLE geolocations")
+        .map_err(|err| err.to_string())?;
+    tx.commit().map_err(|err| err.to_string())?;
+
+    Ok(changed)
+}
+
+/** Most recent timestamp of a source and virtual host */
+pub struct Cursor {
+    pub source: Option<String>,
+    pub vhost: Option<String>,
+    pub timestamp: i64,
+}
+
+/**
+ * Get the most recent timestamp of every source and virtual host of the database.
+ * The exact times saved in _log2duck_cursors are preferred to the truncated timestamps of the log.
+ */
+pub fn cursors(conn: &Connection) -> Result<Vec<Cursor>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT source, vhost, max(timestamp) FROM (
+                SELECT source, vhost, timestamp FROM log
+                UNION ALL
+                SELECT source, vhost, timestamp FROM _log2duck_cursors
+            ) GROUP BY source, vhost",
+        )
+        .map_err(|err| err.to_string())?;
+    let cursors = stmt
+        .query_map([], |row| {
+            Ok(Cursor {
+                source: row.get(0)?,
+                vhost: row.get(1)?,
+                timestamp: row.get(2)?,
+            })
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    Ok(cursors)
+}
+
+/** Exact time (in microseconds) of the last request imported, by source and virtual host */
+pub type LastImported = HashMap<(Option<String>, Option<String>), i64>;
+
+/**
+ * Save the last request imported of every source and virtual host,
+ * so the next import continues after it even if the timestamps are truncated
+ */
+pub fn save_cursors(conn: &Connection, cursors: &LastImported) -> Result<(), String> {
+    let mut stmt = conn
+        .prepare("INSERT INTO _log2duck_cursors VALUES (?, ?, make_timestamp(?))")
+        .map_err(|err| err.to_string())?;
+
+    for ((source, vhost), timestamp) in cursors {
+        stmt.execute(params![source, vhost, timestamp])
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/** Names of the columns of a table */
+pub fn columns(conn: &Connection, table: &str) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT column_name FROM duckdb_columns() WHERE table_name = ? ORDER BY column_index",
+        )
+        .map_err(|err| err.to_string())?;
+    let columns = stmt
+        .query_map(params![table], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    Ok(columns)
+}
+
+/**
+ * Number of requests of every hour between two times, including the hours without requests.
+ * The hours are returned in order, with the format 2024-01-05 13:00:00.
+ */
+pub fn hourly_requests(
+    conn: &Connection,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> Result<Vec<(String, u64)>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT hours.hour::VARCHAR, count(log.timestamp)
+            FROM generate_series(
+                date_trunc('hour', ?::TIMESTAMP),
+                date_trunc('hour', ?::TIMESTAMP),
+                INTERVAL 1 HOUR
+            ) AS hours(hour)
+            LEFT JOIN log ON date_trunc('hour', log.timestamp) = hours.hour
+            GROUP BY hours.hour ORDER BY hours.hour",
+        )
+        .map_err(|err| err.to_string())?;
+    let rows = stmt
+        .query_map(params![timestamp(from), timestamp(to)], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    Ok(rows)
+}
+
+/** Most common values of a column of the log table, with the number of requests */
+pub fn top(
+    conn: &Connection,
+    column: &str,
+    limit: usize,
+) -> Result<Vec<(Option<String>, u64)>, String> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT \"{0}\"::VARCHAR, count(*) FROM log WHERE parse_error IS NULL
+            GROUP BY ALL ORDER BY 2 DESC, 1 LIMIT ?",
+            column
+        ))
+        .map_err(|err| err.to_string())?;
+    let rows = stmt
+        .query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    Ok(rows)
+}
+
+/** Approximate number of distinct values of a column (with HyperLogLog) */
+pub fn count_distinct(conn: &Connection, column: &str) -> Result<u64, String> {
+    conn.query_row(
+        &format!(
+            "SELECT approx_count_distinct(\"{}\") FROM log WHERE parse_error IS NULL",
+            column
+        ),
+        [],
+        |row| row.get(0),
+    )
+    .map_err(|err| err.to_string())
+}
+
+fn table_exists(conn: &Connection, name: &str) -> Result<bool, String> {
+    conn.query_row(
+        "SELECT count(*) > 0 FROM duckdb_tables() WHERE table_name = ?",
+        params![name],
+        |row| row.get(0),
+    )
+    .map_err(|err| err.to_string())
+}
+
+/**
+ * Check that an ENUM type of the database has the same values as the Rust enum,
+ * so a value not supported by the database fails here and not while appending rows.
+ */
+fn check_enum(conn: &Connection, name: &str, expected: &[&str]) -> Result<(), String> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT unnest(enum_range(NULL::{}))::VARCHAR",
+            name
+        ))
+        .map_err(|err| err.to_string())?;
+    let values: Vec<String> = stmt
+        .query_map([], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(|err| err.to_string())?;
+
+    if values != expected {
+        return Err(format!(
+            "The type {} of the database has the values ({}), but ({}) were expected",
+            name,
+            values.join(", "),
+            expected.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/**
+ * Check that the log table has the same columns as LOG_TABLE, in the same order,
+ * so a database with missing columns fails here and not while appending rows.
+ */
+fn check_columns(conn: &Connection) -> Result<(), String> {
+    let expected: Vec<&str> = LOG_TABLE
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.ends_with(','))
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    let columns = columns(conn, "log")?;
+
+    if columns != expected {
+        let missing: Vec<&str> = expected
+            .iter()
+            .filter(|column| !columns.iter().any(|name| name == *column))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            return Err(String::from(
+                "The columns of the log table are different from the ones of this version of log2duck",
+            ));
+        }
+        return Err(format!(
+            "The log table doesn't have the columns {}",
+            missing.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/** Generate the list of values of an ENUM type */
+fn enum_values(values: &[&str]) -> String {
+    values
+        .iter()
+        .map(|value| format!("'{}'", value))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
@@ -0,0 +1,53 @@
+use arrow::ipc::writer::FileWriter;
+use duckdb::Connection;
+use std::error::Error;
+use std::fs::File;
+
+pub const PARQUET_CODECS: [&str; 5] = ["snappy", "zstd", "gzip", "lz4", "uncompressed"];
+
+/** Export the log table to a Parquet file */
+pub fn parquet(
+    conn: &Connection,
+    filename: &str,
+    codec: &str,
+    row_group_size: usize,
+) -> duckdb::Result<()> {
+    conn.execute_batch(&format!(
+        "COPY log TO {} (FORMAT PARQUET, COMPRESSION {}, ROW_GROUP_SIZE {})",
+        quote(filename),
+        codec,
+        row_group_size
+    ))
+}
+
+/** Export the log table to a CSV file with header, using `null` for the NULL values */
+pub fn csv(conn: &Connection, filename: &str, null: &str) -> duckdb::Result<()> {
+    conn.execute_batch(&format!(
+        "COPY log TO {} (FORMAT CSV, HEADER, NULLSTR {})",
+        quote(filename),
+        quote(null)
+    ))
+}
+
+/**
+ * Export the log table to an Arrow IPC (Feather v2) file.
+ * The ENUM columns are written as dictionary-encoded strings.
+ */
+pub fn arrow(conn: &Connection, filename: &str) -> Result<(), Box<dyn Error>> {
+    let mut stmt = conn.prepare("SELECT * FROM log")?;
+    let mut batches = stmt.query_arrow([])?;
+    let schema = batches.get_schema();
+    let mut writer = FileWriter::try_new(File::create(filename)?, &schema)?;
+
+    for batch in &mut batches {
+        writer.write(&batch)?;
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+/** Quote a string to be used as SQL literal */
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
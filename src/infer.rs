@@ -0,0 +1,408 @@
+use crate::options::Options;
+use crate::{count_populated, line_format, parse_config, read_log_file, SCHEMA_SAMPLE_SIZE};
+use log2duck::{detect_origin, parse_lines, JsonKeys, LogFormat, RawEntry};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+
+// Usual keys of every field in the JSON logs, after the default key of `JsonKeys`.
+// They are compared case-insensitively and with `-` and `.` as `_`.
+const JSON_CANDIDATES: [(&str, &[&str]); 14] = [
+    ("vhost", &["http_host", "server_name", "vhost", "hostname"]),
+    ("ip", &["client_ip", "ip", "remote_ip", "clientip", "c_ip"]),
+    ("user", &["user", "username", "auth_user"]),
+    (
+        "timestamp",
+        &[
+            "time_iso8601",
+            "@timestamp",
+            "timestamp",
+            "time",
+            "msec",
+            "ts",
+            "date",
+        ],
+    ),
+    ("request", &["request_line"]),
+    ("method", &["method", "verb", "http_method"]),
+    ("target", &["uri", "url", "path", "target", "request_path"]),
+    (
+        "http_version",
+        &["protocol", "http_version", "http_protocol"],
+    ),
+    (
+        "status_code",
+        &["status_code", "response_code", "code", "http_status"],
+    ),
+    (
+        "size",
+        &[
+            "bytes_sent",
+            "size",
+            "bytes",
+            "response_size",
+            "response_bytes",
+        ],
+    ),
+    ("referer", &["referer", "referrer", "http_referrer"]),
+    ("user_agent", &["user_agent", "agent", "useragent", "ua"]),
+    (
+        "request_time",
+        &["duration", "response_time", "latency", "elapsed"],
+    ),
+    ("upstream_time", &["upstream_time", "upstream_duration"]),
+];
+
+/** Type of the values of a field, from the most specific to the most generic */
+#[derive(Clone, Copy, PartialEq)]
+enum FieldType {
+    Null,
+    Boolean,
+    Integer,
+    Double,
+    Text,
+    Json,
+}
+
+impl FieldType {
+    /** Type of a value of a text log ("-" and empty values are null) */
+    fn of(value: &str) -> FieldType {
+        if value.is_empty() || value == "-" {
+            FieldType::Null
+        } else if value.parse::<i64>().is_ok() {
+            FieldType::Integer
+        } else if value.parse::<f64>().is_ok() {
+            FieldType::Double
+        } else {
+            FieldType::Text
+        }
+    }
+
+    /** Type of a JSON value. The strings are checked like the values of text logs */
+    fn of_json(value: &Value) -> FieldType {
+        match value {
+            Value::Null => FieldType::Null,
+            Value::Bool(_) => FieldType::Boolean,
+            Value::Number(number) if number.is_i64() || number.is_u64() => FieldType::Integer,
+            Value::Number(_) => FieldType::Double,
+            Value::String(value) => FieldType::of(value),
+            Value::Array(_) | Value::Object(_) => FieldType::Json,
+        }
+    }
+
+    /** The type that can store the values of both types */
+    fn merge(self, other: FieldType) -> FieldType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (FieldType::Null, other) | (other, FieldType::Null) => other,
+            (FieldType::Integer, FieldType::Double) | (FieldType::Double, FieldType::Integer) => {
+                FieldType::Double
+            }
+            _ => FieldType::Text,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::Null => "NULL",
+            FieldType::Boolean => "BOOLEAN",
+            FieldType::Integer => "BIGINT",
+            FieldType::Double => "DOUBLE",
+            FieldType::Text => "VARCHAR",
+            FieldType::Json => "JSON",
+        }
+    }
+}
+
+/**
+ * Read the first lines of the log files, infer the type of every field and the keys
+ * of the JSON logs, and parse the lines with them to check the result (used by --infer).
+ * The inferred configuration is printed and, with --infer-output, saved to a TOML file.
+ */
+pub fn infer(options: &mut Options) {
+    let samples: Vec<(usize, Vec<String>)> = options
+        .sources
+        .iter()
+        .enumerate()
+        .map(|(index, source)| {
+            let lines = read_log_file(&source.input, options.format).take(SCHEMA_SAMPLE_SIZE);
+            (index, lines.collect())
+        })
+        .collect();
+    let sampled: usize = samples.iter().map(|(_, lines)| lines.len()).sum();
+
+    println!("{} lines sampled", sampled);
+    println!();
+
+    let mut types: BTreeMap<String, FieldType> = BTreeMap::new();
+    let mut json_keys: Vec<(&str, Option<String>)> = Vec::new();
+
+    match options.format {
+        LogFormat::Json | LogFormat::Cloudflare => {
+            for (_, lines) in &samples {
+                for line in lines {
+                    if let Ok(fields) = serde_json::from_str::<Map<String, Value>>(line) {
+                        for (key, value) in &fields {
+                            let field_type = types.entry(key.clone()).or_insert(FieldType::Null);
+                            *field_type = field_type.merge(FieldType::of_json(value));
+                        }
+                    }
+                }
+            }
+
+            if options.format == LogFormat::Json {
+                let keys = options.json_keys.get_or_insert_with(JsonKeys::default);
+                json_keys = infer_json_keys(keys, &types);
+                print_json_keys(&json_keys, keys, &types);
+            } else {
+                print_types("Key", &types);
+            }
+        }
+        // The fields of CloudFront are not split by RawEntry
+        LogFormat::CloudFront => println!("The types of the CloudFront fields are not inferred"),
+        LogFormat::Combined | LogFormat::S3 => {
+            let format = line_format(options);
+            let mut variables: BTreeMap<String, FieldType> = BTreeMap::new();
+
+            for (_, lines) in &samples {
+                for raw in lines
+                    .iter()
+                    .filter_map(|line| RawEntry::parse(line, &format).ok())
+                {
+                    for (name, value) in raw
+                        .fields()
+                        .into_iter()
+                        .filter(|(name, _)| *name != "extra")
+                    {
+                        let field_type = types.entry(name.to_string()).or_insert(FieldType::Null);
+                        *field_type = field_type.merge(FieldType::of(value));
+                    }
+                    for (name, value) in &raw.variables {
+                        let field_type = variables.entry(name.clone()).or_insert(FieldType::Null);
+                        *field_type = field_type.merge(FieldType::of(value));
+                    }
+                }
+            }
+
+            print_types("Field", &types);
+            if !variables.is_empty() {
+                println!();
+                print_types("Variable (extra)", &variables);
+            }
+            types.extend(variables);
+        }
+    }
+
+    let mut arguments = format!("--format {}", format_name(options.format));
+    let defaults = JsonKeys::default();
+    let changed: Vec<String> = json_keys
+        .iter()
+        .filter_map(|(field, key)| {
+            let key = key.as_deref()?;
+            (defaults.get(field) != Some(key)).then(|| format!("{}={}", field, key))
+        })
+        .collect();
+    if !changed.is_empty() {
+        let _ = write!(arguments, " --json-keys {}", changed.join(","));
+    }
+    println!();
+    println!("Options: {}", arguments);
+
+    // Parse the sample with the inferred configuration
+    let format = line_format(options);
+    let mut services = crate::pipeline::services(options);
+    let mut populated: BTreeMap<String, usize> = BTreeMap::new();
+    let mut parsed = 0;
+    let mut errors = 0;
+
+    for (index, lines) in samples {
+        let source = &mut options.sources[index];
+        if source.origin == "auto" {
+            match detect_origin(lines.iter().cloned(), &format) {
+                Some(origin) => source.origin = origin,
+                None => {
+                    println!(
+                        "Error: Unable to detect the origin of {}, please pass it explicitly",
+                        source.input
+                    );
+                    return;
+                }
+            }
+        }
+
+        let source = &options.sources[index];
+        let config = parse_config(options, &[], source);
+        for (number, result) in parse_lines(lines.into_iter(), &mut services, &config) {
+            match result {
+                Ok(log) => {
+                    parsed += 1;
+                    count_populated(&log, &mut populated);
+                }
+                Err(error) if !error.is_filtered() => {
+                    errors += 1;
+                    if errors <= 10 {
+                        println!("{}:{} {}", source.input, number, error);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    println!();
+    println!("{} lines parsed, {} errors", parsed, errors);
+    println!();
+    println!("  {:<20} {:>10}", "Column", "Populated");
+    for (column, count) in &populated {
+        println!("  {:<20} {:>10}", column, count);
+    }
+
+    if let Some(filename) = &options.infer_output {
+        let toml = to_toml(options.format, &json_keys, &types);
+        match fs::write(filename, toml) {
+            Ok(_) => println!("Inferred configuration saved to {}", filename),
+            Err(error) => println!("Error: Unable to write {} ({})", filename, error),
+        }
+    }
+}
+
+/**
+ * Find the key of every field in the sample: the configured key if it's found,
+ * or the first candidate found (the field is missing if there's none).
+ */
+fn infer_json_keys(
+    keys: &mut JsonKeys,
+    types: &BTreeMap<String, FieldType>,
+) -> Vec<(&'static str, Option<String>)> {
+    let normalize = |key: &str| key.to_lowercase().replace(['-', '.'], "_");
+
+    JsonKeys::FIELDS
+        .iter()
+        .map(|field| {
+            let current = keys.get(field).unwrap_or_default().to_string();
+            let candidates = JSON_CANDIDATES
+                .iter()
+                .find(|(name, _)| name == field)
+                .map_or(&[][..], |(_, candidates)| candidates);
+            let key = if types.contains_key(&current) {
+                Some(current)
+            } else {
+                candidates.iter().find_map(|candidate| {
+                    types
+                        .keys()
+                        .find(|key| normalize(key) == normalize(candidate))
+                        .cloned()
+                })
+            };
+
+            if let Some(key) = &key {
+                keys.set(field, key).unwrap();
+            }
+            (*field, key)
+        })
+        .collect()
+}
+
+/** Print the key and the type of every field, and the keys stored in extra */
+fn print_json_keys(
+    mapping: &[(&str, Option<String>)],
+    keys: &JsonKeys,
+    types: &BTreeMap<String, FieldType>,
+) {
+    println!("  {:<14} {:<24} Type", "Field", "Key");
+    for (field, key) in mapping {
+        let field_type = key.as_ref().and_then(|key| types.get(key));
+        println!(
+            "  {:<14} {:<24} {}",
+            field,
+            key.as_deref().unwrap_or("-"),
+            field_type.map_or("-", FieldType::name)
+        );
+    }
+
+    let extra: BTreeMap<String, FieldType> = types
+        .iter()
+        .filter(|(key, _)| {
+            !JsonKeys::FIELDS
+                .iter()
+                .any(|field| keys.get(field) == Some(key))
+        })
+        .map(|(key, field_type)| (key.clone(), *field_type))
+        .collect();
+    if !extra.is_empty() {
+        println!();
+        print_types("Key (extra)", &extra);
+    }
+}
+
+fn print_types(title: &str, types: &BTreeMap<String, FieldType>) {
+    println!("  {:<24} Type", title);
+    for (name, field_type) in types {
+        println!("  {:<24} {}", name, field_type.name());
+    }
+}
+
+fn format_name(format: LogFormat) -> &'static str {
+    match format {
+        LogFormat::Combined => "combined",
+        LogFormat::Cloudflare => "cloudflare",
+        LogFormat::Json => "json",
+        LogFormat::S3 => "s3",
+        LogFormat::CloudFront => "cloudfront",
+    }
+}
+
+/**
+ * The inferred configuration, as TOML: the format, the keys of the JSON fields
+ * (the values of --json-keys) and the type of every field of the sample.
+ */
+fn to_toml(
+    format: LogFormat,
+    json_keys: &[(&str, Option<String>)],
+    types: &BTreeMap<String, FieldType>,
+) -> String {
+    let mut toml = String::from("# Generated by log2duck --infer\n");
+    let _ = writeln!(toml, "format = {}", toml_string(format_name(format)));
+
+    if !json_keys.is_empty() {
+        toml.push_str("\n[json_keys]\n");
+        for (field, key) in json_keys {
+            if let Some(key) = key {
+                let _ = writeln!(toml, "{} = {}", field, toml_string(key));
+            }
+        }
+    }
+
+    if !types.is_empty() {
+        toml.push_str("\n[fields]\n");
+        for (name, field_type) in types {
+            let _ = writeln!(
+                toml,
+                "{} = {}",
+                toml_key(name),
+                toml_string(field_type.name())
+            );
+        }
+    }
+
+    toml
+}
+
+/** The JSON strings are valid TOML basic strings */
+fn toml_string(value: &str) -> String {
+    Value::from(value).to_string()
+}
+
+/** The keys with other characters than letters, digits, `_` and `-` must be quoted */
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        toml_string(key)
+    }
+}
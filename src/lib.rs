@@ -1,11 +1,17 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, DurationRound, FixedOffset, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
 use maxminddb::Reader;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
 use std::fmt;
 use std::net::IpAddr;
 use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, error::Error};
 use ua_parser::{device, os, user_agent, Extractor, Regexes};
-use url::{Origin, Url};
+use url::{form_urlencoded, Origin, Url};
 
 struct ParseError {}
 
@@ -29,6 +35,18 @@ pub enum HttpMethod {
 }
 
 impl HttpMethod {
+    pub const ALL: [HttpMethod; 9] = [
+        HttpMethod::GET,
+        HttpMethod::POST,
+        HttpMethod::PUT,
+        HttpMethod::DELETE,
+        HttpMethod::HEAD,
+        HttpMethod::OPTIONS,
+        HttpMethod::CONNECT,
+        HttpMethod::TRACE,
+        HttpMethod::PATCH,
+    ];
+
     fn new(method: &str) -> Result<HttpMethod, ParseError> {
         match method {
             "GET" => Ok(HttpMethod::GET),
@@ -68,6 +86,13 @@ pub enum HttpVersion {
 }
 
 impl HttpVersion {
+    pub const ALL: [HttpVersion; 4] = [
+        HttpVersion::HTTP10,
+        HttpVersion::HTTP11,
+        HttpVersion::HTTP20,
+        HttpVersion::HTTP30,
+    ];
+
     fn new(version: &str) -> Result<HttpVersion, ParseError> {
         match version {
             "HTTP/1.0" => Ok(HttpVersion::HTTP10),
@@ -88,36 +113,1016 @@ impl HttpVersion {
     }
 }
 
+/// Options to parse the lines. It's cheap to clone, so every worker parsing in parallel can have a copy.
+#[derive(Clone)]
 pub struct ParseConfig {
     timestamp: i64,
+    vhost_timestamps: HashMap<String, i64>,
     origin: Url,
+    origins: Vec<Url>,
+    format: LineFormat,
+    countries: ListFilter,
+    continents: ListFilter,
+    max_query_length: Option<usize>,
+    lowercase_path: bool,
+    visitor_salt: Option<String>,
+    session_cookie: Option<String>,
+    redacted_params: Vec<String>,
+    match_line: Option<Regex>,
+    exclude_line: Option<Regex>,
+    time_bucket: Option<TimeBucket>,
+    asset_extensions: Vec<String>,
+    path_prefix: Option<String>,
+    min_status: Option<u16>,
+    max_status: Option<u16>,
+    number_separators: Vec<char>,
+    dedup_consecutive: bool,
+    routes: Option<RoutePatterns>,
+    canonical_query: bool,
+    normalize_ua: bool,
 }
 
+/// Extensions of the static files, used by default to detect the assets
+pub const ASSET_EXTENSIONS: [&str; 24] = [
+    "css", "js", "mjs", "map", "json", "xml", "txt", "png", "jpg", "jpeg", "gif", "webp", "avif",
+    "svg", "ico", "woff", "woff2", "ttf", "otf", "eot", "mp4", "webm", "mp3", "pdf",
+];
+
+// Value of the redacted query parameters
+const REDACTED: &str = "REDACTED";
+
+// Appended to the queries truncated by the max query length
+const TRUNCATED_MARKER: &str = "[...]";
+
 impl ParseConfig {
     pub fn new(timestamp: i64, origin: &str) -> ParseConfig {
         ParseConfig {
             timestamp,
+            vhost_timestamps: HashMap::new(),
             origin: Url::parse(origin).unwrap(),
+            origins: Vec::new(),
+            format: LineFormat::default(),
+            countries: ListFilter::default(),
+            continents: ListFilter::default(),
+            max_query_length: None,
+            lowercase_path: false,
+            visitor_salt: None,
+            session_cookie: None,
+            redacted_params: Vec::new(),
+            match_line: None,
+            exclude_line: None,
+            time_bucket: None,
+            asset_extensions: ASSET_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            path_prefix: None,
+            min_status: None,
+            max_status: None,
+            number_separators: Vec::new(),
+            dedup_consecutive: false,
+            routes: None,
+            canonical_query: false,
+            normalize_ua: false,
+        }
+    }
+
+    pub fn with_format(mut self, format: LineFormat) -> ParseConfig {
+        self.format = format;
+        self
+    }
+
+    /// The config to parse the lines after a `#Fields` header of the CloudFront logs
+    /// (see `LineFormat::after_header`). Returns `None` if the line is not a header.
+    /// `parse_lines` reads the headers, but the callers parsing the lines one by one
+    /// must call it with every line.
+    pub fn after_header(&self, line: &str) -> Option<ParseConfig> {
+        let format = self.format.after_header(line)?;
+        Some(ParseConfig {
+            format,
+            ..self.clone()
+        })
+    }
+
+    /// Use a different cursor per virtual host, so the entries of a host are filtered
+    /// by the most recent timestamp of that host. The hosts not included use the default timestamp.
+    pub fn with_vhost_timestamps(mut self, timestamps: HashMap<String, i64>) -> ParseConfig {
+        self.vhost_timestamps = timestamps;
+        self
+    }
+
+    /// Accept the absolute request targets to the hosts of these origins too,
+    /// for logs of servers with several domains. The paths are still resolved with the origin.
+    pub fn with_origins(mut self, origins: Vec<Url>) -> ParseConfig {
+        self.origins = origins;
+        self
+    }
+
+    /// Filter the entries by the country (code or name) of the IP
+    pub fn with_countries(mut self, countries: ListFilter) -> ParseConfig {
+        self.countries = countries;
+        self
+    }
+
+    /// Filter the entries by the continent (code or name) of the IP
+    pub fn with_continents(mut self, continents: ListFilter) -> ParseConfig {
+        self.continents = continents;
+        self
+    }
+
+    /// Truncate the queries of the request and referer URLs longer than this number of characters.
+    /// The truncated queries end with `[...]`.
+    pub fn with_max_query_length(mut self, length: usize) -> ParseConfig {
+        self.max_query_length = Some(length);
+        self
+    }
+
+    /// Lowercase the paths, for servers with case-insensitive URLs
+    pub fn with_lowercase_path(mut self, lowercase: bool) -> ParseConfig {
+        self.lowercase_path = lowercase;
+        self
+    }
+
+    /// Calculate the visitor id of the entries with this salt (see `visitor_id`)
+    pub fn with_visitor_id(mut self, salt: &str) -> ParseConfig {
+        self.visitor_salt = Some(salt.to_string());
+        self
+    }
+
+    /// Store in `session_id` the value of this cookie of the `Cookie` header (a template field).
+    /// Only this cookie is stored, never the whole header.
+    pub fn with_session_cookie(mut self, name: &str) -> ParseConfig {
+        self.session_cookie = Some(name.to_string());
+        self
+    }
+
+    /// Replace the values of these query parameters (like tokens or passwords) with `REDACTED`,
+    /// in the request and referer URLs. The names are compared case-insensitively.
+    pub fn with_redacted_params(mut self, params: Vec<String>) -> ParseConfig {
+        self.redacted_params = params;
+        self
+    }
+
+    /// Filter the lines with regular expressions, before parsing them.
+    /// Only the lines matching `only` and not matching `exclude` are parsed.
+    pub fn with_line_regex(mut self, only: Option<Regex>, exclude: Option<Regex>) -> ParseConfig {
+        self.match_line = only;
+        self.exclude_line = exclude;
+        self
+    }
+
+    /// Truncate the timestamps to this resolution
+    pub fn with_time_bucket(mut self, bucket: TimeBucket) -> ParseConfig {
+        self.time_bucket = Some(bucket);
+        self
+    }
+
+    /// Calculate the canonical form of the queries, to group the URLs with the same parameters
+    pub fn with_canonical_query(mut self, canonical: bool) -> ParseConfig {
+        self.canonical_query = canonical;
+        self
+    }
+
+    /// Calculate a user agent key without the minor versions, like `Chrome 120 on Windows`
+    pub fn with_normalized_ua(mut self, normalize: bool) -> ParseConfig {
+        self.normalize_ua = normalize;
+        self
+    }
+
+    /// Calculate the route of the paths (`/users/123` -> `/users/{id}`)
+    pub fn with_routes(mut self, routes: RoutePatterns) -> ParseConfig {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// Extensions of the paths considered static assets (instead of `ASSET_EXTENSIONS`)
+    pub fn with_asset_extensions(mut self, extensions: Vec<String>) -> ParseConfig {
+        self.asset_extensions = extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Filter the requests outside a subtree of the site: `/app` accepts `/app` and `/app/*`,
+    /// but not `/application`
+    pub fn with_path_prefix(mut self, prefix: &str) -> ParseConfig {
+        self.path_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Filter the requests with the status code out of this range (both inclusive)
+    pub fn with_status_range(mut self, min: Option<u16>, max: Option<u16>) -> ParseConfig {
+        self.min_status = min;
+        self.max_status = max;
+        self
+    }
+
+    /// Remove these characters (like thousands separators) from the status code and the size
+    /// before parsing them, for formats that log `1,234` or `1.234`
+    pub fn with_number_separators(mut self, separators: &str) -> ParseConfig {
+        self.number_separators = separators.chars().collect();
+        self
+    }
+
+    /// Filter the lines identical to the previous one (logged twice by some buggy loggers).
+    /// Only the consecutive duplicates are detected, and only by `parse_lines`
+    /// (use `mark_duplicates` to detect them when the lines are parsed one by one).
+    pub fn with_dedup_consecutive(mut self, dedup: bool) -> ParseConfig {
+        self.dedup_consecutive = dedup;
+        self
+    }
+
+    fn accepts_path(&self, path: &str) -> bool {
+        match &self.path_prefix {
+            None => true,
+            Some(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        }
+    }
+
+    fn accepts_status(&self, status_code: u16) -> bool {
+        self.min_status.is_none_or(|min| status_code >= min)
+            && self.max_status.is_none_or(|max| status_code <= max)
+    }
+
+    /// Check whether the line passes the regular expressions
+    fn accepts_line(&self, line: &str) -> bool {
+        self.match_line.as_ref().is_none_or(|re| re.is_match(line))
+            && !self
+                .exclude_line
+                .as_ref()
+                .is_some_and(|re| re.is_match(line))
+    }
+
+    /// Redact the query parameters of the URL, keeping the rest of the query as is
+    fn redact_query(&self, url: &mut Url) {
+        if self.redacted_params.is_empty() {
+            return;
+        }
+
+        let query = match url.query() {
+            Some(query) => query.to_string(),
+            None => return,
+        };
+
+        let pairs: Vec<String> = query
+            .split('&')
+            .map(|pair| {
+                let key = pair.split_once('=').map_or(pair, |(key, _)| key);
+                let name = query_pairs(key)
+                    .next()
+                    .map(|(name, _)| name)
+                    .unwrap_or_default();
+
+                if self
+                    .redacted_params
+                    .iter()
+                    .any(|param| param.eq_ignore_ascii_case(&name))
+                {
+                    format!("{}={}", key, REDACTED)
+                } else {
+                    pair.to_string()
+                }
+            })
+            .collect();
+
+        url.set_query(Some(&pairs.join("&")));
+    }
+
+    fn unescape<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        if self.format.escaped {
+            unescape(value)
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+
+    fn strip_separators<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        if value.contains(self.number_separators.as_slice()) {
+            Cow::Owned(value.replace(self.number_separators.as_slice(), ""))
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+
+    fn truncate_query(&self, query: &str) -> String {
+        match self.max_query_length {
+            Some(length) => match query.char_indices().nth(length) {
+                Some((end, _)) => format!("{}{}", &query[..end], TRUNCATED_MARKER),
+                None => query.to_string(),
+            },
+            None => query.to_string(),
+        }
+    }
+}
+
+/// Resolution of the stored timestamps
+#[derive(Clone, Copy)]
+pub enum TimeBucket {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl TimeBucket {
+    pub fn new(bucket: &str) -> Result<TimeBucket, String> {
+        match bucket {
+            "minute" => Ok(TimeBucket::Minute),
+            "hour" => Ok(TimeBucket::Hour),
+            "day" => Ok(TimeBucket::Day),
+            _ => Err(format!(
+                "Invalid time bucket {} (valid: minute, hour, day)",
+                bucket
+            )),
+        }
+    }
+
+    /// Truncate the timestamp to the start of the bucket (in UTC)
+    pub fn truncate(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let duration = match self {
+            TimeBucket::Minute => TimeDelta::minutes(1),
+            TimeBucket::Hour => TimeDelta::hours(1),
+            TimeBucket::Day => TimeDelta::days(1),
+        };
+
+        timestamp.duration_trunc(duration).unwrap_or(timestamp)
+    }
+}
+
+/// Patterns to group the paths by route, like `/users/{id}/posts/{id}`.
+/// A `{name}` segment matches any segment and a final `*` matches the rest of the path.
+/// The paths not matching any pattern get a route with the ids replaced by `{id}`.
+#[derive(Clone, Default)]
+pub struct RoutePatterns {
+    patterns: Vec<String>,
+}
+
+impl RoutePatterns {
+    /// The patterns are checked in order, and the first one matching is used
+    pub fn new(patterns: Vec<String>) -> RoutePatterns {
+        RoutePatterns { patterns }
+    }
+
+    /// Parse a file with a pattern per line. Empty lines and lines starting with # are ignored.
+    pub fn parse(content: &str) -> RoutePatterns {
+        RoutePatterns::new(
+            content
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())
+                .collect(),
+        )
+    }
+
+    pub fn route(&self, path: &str) -> String {
+        self.patterns
+            .iter()
+            .find(|pattern| route_matches(pattern, path))
+            .cloned()
+            .unwrap_or_else(|| {
+                path.split('/')
+                    .map(|segment| if is_id(segment) { "{id}" } else { segment })
+                    .collect::<Vec<&str>>()
+                    .join("/")
+            })
+    }
+}
+
+fn route_matches(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/');
+
+    for expected in pattern.split('/') {
+        if expected == "*" {
+            return true;
+        }
+        let matches = match segments.next() {
+            Some(segment) if expected.starts_with('{') && expected.ends_with('}') => {
+                !segment.is_empty()
+            }
+            Some(segment) => segment == expected,
+            None => false,
+        };
+        if !matches {
+            return false;
+        }
+    }
+
+    segments.next().is_none()
+}
+
+/// Segments of the path that are ids: numbers, UUIDs and hashes (hexadecimal with 16+ characters)
+fn is_id(segment: &str) -> bool {
+    let hex = |value: &str| value.chars().all(|c| c.is_ascii_hexdigit());
+
+    if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+        return true;
+    }
+
+    let groups: Vec<&str> = segment.split('-').collect();
+    if groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12]) && groups.iter().all(|g| hex(g))
+    {
+        return true;
+    }
+
+    segment.len() >= 16 && hex(segment) && segment.chars().any(|c| c.is_ascii_digit())
+}
+
+/// Base format of the lines
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    /// Combined log format of Apache and nginx
+    #[default]
+    Combined,
+    /// JSON objects of Cloudflare Logpush (HTTP requests dataset)
+    Cloudflare,
+    /// JSON objects with the keys of `JsonKeys`
+    Json,
+    /// Server access logs of AWS S3
+    S3,
+    /// Standard logs of AWS CloudFront (tab-separated)
+    CloudFront,
+}
+
+impl LogFormat {
+    pub fn new(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "combined" => Ok(LogFormat::Combined),
+            "cloudflare" => Ok(LogFormat::Cloudflare),
+            "json" => Ok(LogFormat::Json),
+            "s3" => Ok(LogFormat::S3),
+            "cloudfront" => Ok(LogFormat::CloudFront),
+            _ => Err(format!(
+                "Invalid format {} (valid: combined, cloudflare, json, s3, cloudfront)",
+                format
+            )),
+        }
+    }
+}
+
+/// Variations of the combined log format
+#[derive(Clone)]
+pub struct LineFormat {
+    /// Base format of the lines (the rest of options only apply to the combined format)
+    pub base: LogFormat,
+    /// The lines start with the virtual host and port (Apache's vhost_combined format)
+    pub vhost: bool,
+    /// Character wrapping the request, referer and user agent (double quotes by default)
+    pub quote: char,
+    /// Character separating the fields (a space by default, or a tab)
+    pub delimiter: char,
+    /// The lines start with a syslog header (RFC 3164 or RFC 5424) that is ignored
+    pub syslog: bool,
+    /// The quoted fields are escaped (like nginx's `escape=default` or `escape=json`):
+    /// the escaped quotes don't close the fields and the values are unescaped
+    pub escaped: bool,
+    /// Template of the fields, instead of the combined format (nginx's `log_format` or Apache's `LogFormat`)
+    pub template: Option<Arc<LogTemplate>>,
+    /// Keys of the fields of the JSON format
+    pub json_keys: Arc<JsonKeys>,
+    /// Fields of the CloudFront logs, from the last `#Fields` header read (see `after_header`)
+    pub cloudfront_fields: Option<Arc<CloudFrontFields>>,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        LineFormat {
+            base: LogFormat::Combined,
+            vhost: false,
+            quote: '"',
+            delimiter: ' ',
+            syslog: false,
+            escaped: false,
+            template: None,
+            json_keys: Arc::new(JsonKeys::default()),
+            cloudfront_fields: None,
+        }
+    }
+}
+
+impl LineFormat {
+    /// The format of the lines after a `#Fields` header of the CloudFront logs,
+    /// with the fields of the header. Returns `None` if the line is not a header.
+    pub fn after_header(&self, line: &str) -> Option<LineFormat> {
+        if self.base != LogFormat::CloudFront {
+            return None;
         }
+
+        let fields = CloudFrontFields::from_header(line)?;
+        Some(LineFormat {
+            cloudfront_fields: Some(Arc::new(fields)),
+            ..self.clone()
+        })
+    }
+
+    /// Template to split the lines: the one of the options or the one of the S3 logs
+    fn fields_template(&self) -> Option<&LogTemplate> {
+        match self.base {
+            LogFormat::S3 => Some(s3_template()),
+            _ => self.template.as_deref(),
+        }
+    }
+}
+
+// Fields of the S3 server access logs, up to the user agent.
+// The newer fields are added after it, so they are split separately
+const S3_TEMPLATE: &str = r#"%{bucket_owner}x %{bucket}x [%t] %h %{requester}x %{request_id}x %{operation}x %{key}x "%r" %>s %{error_code}x %b %{object_size}x %{ms}T %{turn_around_time}x "%{Referer}i" "%{User-Agent}i""#;
+
+// Fields of the S3 server access logs after the user agent, the older logs don't have all of them
+const S3_TRAILING_FIELDS: [&str; 9] = [
+    "version_id",
+    "host_id",
+    "signature_version",
+    "cipher_suite",
+    "authentication_type",
+    "host_header",
+    "tls_version",
+    "access_point_arn",
+    "acl_required",
+];
+
+fn s3_template() -> &'static LogTemplate {
+    static TEMPLATE: OnceLock<LogTemplate> = OnceLock::new();
+    TEMPLATE.get_or_init(|| LogTemplate::new(S3_TEMPLATE).unwrap())
+}
+
+/// Positions of the fields of the CloudFront standard logs, read from the `#Fields` header
+/// (`#Fields: date time x-edge-location sc-bytes c-ip ...`), because every distribution
+/// can log a different set of fields.
+#[derive(Debug, Clone)]
+pub struct CloudFrontFields {
+    positions: HashMap<String, usize>,
+}
+
+impl CloudFrontFields {
+    /// Parse a `#Fields` header. Returns `None` if the line is not a header.
+    pub fn from_header(line: &str) -> Option<CloudFrontFields> {
+        let names = line.strip_prefix("#Fields:")?;
+        let positions = names
+            .split_whitespace()
+            .enumerate()
+            .map(|(position, name)| (name.to_string(), position))
+            .collect();
+
+        Some(CloudFrontFields { positions })
+    }
+
+    /// Split a tab-separated line, returning the value of every field by its name
+    fn split<'a>(&self, line: &'a str) -> HashMap<&str, &'a str> {
+        let values: Vec<&str> = line.split('\t').collect();
+
+        self.positions
+            .iter()
+            .filter_map(|(name, position)| Some((name.as_str(), *values.get(*position)?)))
+            .collect()
+    }
+
+    /// Value of a field of a tab-separated line
+    fn get<'a>(&self, line: &'a str, name: &str) -> Option<&'a str> {
+        let position = *self.positions.get(name)?;
+        line.split('\t').nth(position)
+    }
+}
+
+/// Keys of the fields of the JSON logs (`--format json`), by default the names of the
+/// nginx variables (`remote_addr`, `time_local`, `request`, `status`...)
+#[derive(Debug, Clone)]
+pub struct JsonKeys {
+    pub vhost: String,
+    pub ip: String,
+    pub user: String,
+    pub timestamp: String,
+    pub request: String,
+    pub method: String,
+    pub target: String,
+    pub http_version: String,
+    pub status_code: String,
+    pub size: String,
+    pub referer: String,
+    pub user_agent: String,
+    pub request_time: String,
+    pub upstream_time: String,
+}
+
+impl Default for JsonKeys {
+    fn default() -> Self {
+        JsonKeys {
+            vhost: String::from("host"),
+            ip: String::from("remote_addr"),
+            user: String::from("remote_user"),
+            timestamp: String::from("time_local"),
+            request: String::from("request"),
+            method: String::from("request_method"),
+            target: String::from("request_uri"),
+            http_version: String::from("server_protocol"),
+            status_code: String::from("status"),
+            size: String::from("body_bytes_sent"),
+            referer: String::from("http_referer"),
+            user_agent: String::from("http_user_agent"),
+            request_time: String::from("request_time"),
+            upstream_time: String::from("upstream_response_time"),
+        }
+    }
+}
+
+impl JsonKeys {
+    /// Names of the fields, in the order of the combined format
+    pub const FIELDS: [&'static str; 14] = [
+        "vhost",
+        "ip",
+        "user",
+        "timestamp",
+        "request",
+        "method",
+        "target",
+        "http_version",
+        "status_code",
+        "size",
+        "referer",
+        "user_agent",
+        "request_time",
+        "upstream_time",
+    ];
+
+    /// Key of a field, for example `get("ip")` returns `remote_addr` by default
+    pub fn get(&self, field: &str) -> Option<&str> {
+        let value = match field {
+            "vhost" => &self.vhost,
+            "ip" => &self.ip,
+            "user" => &self.user,
+            "timestamp" => &self.timestamp,
+            "request" => &self.request,
+            "method" => &self.method,
+            "target" => &self.target,
+            "http_version" => &self.http_version,
+            "status_code" => &self.status_code,
+            "size" => &self.size,
+            "referer" => &self.referer,
+            "user_agent" => &self.user_agent,
+            "request_time" => &self.request_time,
+            "upstream_time" => &self.upstream_time,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Change the key of a field, for example `set("ip", "client_ip")`
+    pub fn set(&mut self, field: &str, key: &str) -> Result<(), String> {
+        let value = match field {
+            "vhost" => &mut self.vhost,
+            "ip" => &mut self.ip,
+            "user" => &mut self.user,
+            "timestamp" => &mut self.timestamp,
+            "request" => &mut self.request,
+            "method" => &mut self.method,
+            "target" => &mut self.target,
+            "http_version" => &mut self.http_version,
+            "status_code" => &mut self.status_code,
+            "size" => &mut self.size,
+            "referer" => &mut self.referer,
+            "user_agent" => &mut self.user_agent,
+            "request_time" => &mut self.request_time,
+            "upstream_time" => &mut self.upstream_time,
+            _ => {
+                return Err(format!(
+                    "Invalid JSON field {} (valid: {})",
+                    field,
+                    JsonKeys::FIELDS.join(", ")
+                ))
+            }
+        };
+        *value = key.to_string();
+        Ok(())
+    }
+}
+
+/// Format of the lines defined by a template, with the variables of nginx's `log_format`
+/// (`$remote_addr - $remote_user [$time_local] "$request" $status ...`) or the directives
+/// of Apache's `LogFormat` (`%h %l %u %t "%r" %>s %b ...`). The variables must be separated
+/// by some text, that is used to split the line. The variables without a column
+/// (like `$connection_requests`) are stored in `extra`.
+#[derive(Debug)]
+pub struct LogTemplate {
+    parts: Vec<TemplatePart>,
+    /// Seconds of the unit of the request time (Apache's `%D` is in microseconds)
+    request_time_unit: f64,
+}
+
+#[derive(Debug)]
+enum TemplatePart {
+    Literal(String),
+    Field(TemplateField),
+}
+
+#[derive(Debug, PartialEq)]
+enum TemplateField {
+    Vhost,
+    Ip,
+    Identity,
+    User,
+    Timestamp,
+    Request,
+    Method,
+    Target,
+    HttpVersion,
+    Status,
+    Size,
+    Referer,
+    UserAgent,
+    RequestTime,
+    UpstreamTime,
+    /// The `Cookie` header, only used to get the session cookie
+    Cookie,
+    ContentType,
+    ServerPort,
+    /// Id of the request, to correlate it with the logs of the application
+    TraceId,
+    /// Status of the connection after the response: `+` (kept alive), `-` (closed) or `X` (aborted)
+    ConnectionStatus,
+    /// The `Connection` header of the response (`keep-alive` or `close`)
+    Connection,
+    ForwardedFor,
+    Scheme,
+    RequestLength,
+    BytesSent,
+    UpstreamAddr,
+    CacheStatus,
+    SslProtocol,
+    SslCipher,
+    /// Other variable, stored in `extra` with this name
+    Other(String),
+}
+
+impl LogTemplate {
+    pub fn new(template: &str) -> Result<LogTemplate, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut request_time_unit = 1.0;
+        let mut rest = template;
+
+        while let Some(c) = rest.chars().next() {
+            let (field, next) = match c {
+                '$' => {
+                    let name = &rest[1..];
+                    let end = name
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(name.len());
+                    if end == 0 {
+                        literal.push(c);
+                        rest = name;
+                        continue;
+                    }
+                    (nginx_field(&name[..end]), &name[end..])
+                }
+                '%' => {
+                    if let Some(next) = rest.strip_prefix("%%") {
+                        literal.push(c);
+                        rest = next;
+                        continue;
+                    }
+                    let (field, unit, next) = apache_field(rest)?;
+                    if let Some(unit) = unit {
+                        request_time_unit = unit;
+                    }
+                    (field, next)
+                }
+                _ => {
+                    literal.push(c);
+                    rest = &rest[c.len_utf8()..];
+                    continue;
+                }
+            };
+
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            } else if let Some(TemplatePart::Field(_)) = parts.last() {
+                return Err(format!(
+                    "The variables of the format must be separated by some text: {}",
+                    template
+                ));
+            }
+            parts.push(TemplatePart::Field(field));
+            rest = next;
+        }
+
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        let has = |field: TemplateField| {
+            parts
+                .iter()
+                .any(|part| matches!(part, TemplatePart::Field(f) if *f == field))
+        };
+        if !has(TemplateField::Ip) || !has(TemplateField::Timestamp) || !has(TemplateField::Status)
+        {
+            return Err(String::from(
+                "The format must include the IP, the time and the status ($remote_addr, $time_local and $status, or %h, %t and %>s)",
+            ));
+        }
+        let request = has(TemplateField::Request)
+            || (has(TemplateField::Method)
+                && has(TemplateField::Target)
+                && has(TemplateField::HttpVersion));
+        if !request {
+            return Err(String::from(
+                "The format must include the request ($request or %r), or the method, the URI and the protocol",
+            ));
+        }
+
+        Ok(LogTemplate {
+            parts,
+            request_time_unit,
+        })
+    }
+}
+
+/// Field of a variable of nginx's `log_format`
+fn nginx_field(name: &str) -> TemplateField {
+    match name {
+        "host" | "http_host" | "server_name" => TemplateField::Vhost,
+        "remote_addr" => TemplateField::Ip,
+        "remote_user" => TemplateField::User,
+        "time_local" | "time_iso8601" | "msec" => TemplateField::Timestamp,
+        "request" => TemplateField::Request,
+        "request_method" => TemplateField::Method,
+        "request_uri" => TemplateField::Target,
+        "server_protocol" => TemplateField::HttpVersion,
+        "status" => TemplateField::Status,
+        "body_bytes_sent" => TemplateField::Size,
+        "http_referer" => TemplateField::Referer,
+        "http_user_agent" => TemplateField::UserAgent,
+        "request_time" => TemplateField::RequestTime,
+        "upstream_response_time" => TemplateField::UpstreamTime,
+        "http_cookie" => TemplateField::Cookie,
+        "sent_http_content_type" => TemplateField::ContentType,
+        "server_port" => TemplateField::ServerPort,
+        "request_id" | "http_x_request_id" => TemplateField::TraceId,
+        "connection_status" => TemplateField::ConnectionStatus,
+        "sent_http_connection" => TemplateField::Connection,
+        "http_x_forwarded_for" => TemplateField::ForwardedFor,
+        "scheme" => TemplateField::Scheme,
+        "request_length" => TemplateField::RequestLength,
+        "bytes_sent" => TemplateField::BytesSent,
+        "upstream_addr" => TemplateField::UpstreamAddr,
+        "upstream_cache_status" => TemplateField::CacheStatus,
+        "ssl_protocol" => TemplateField::SslProtocol,
+        "ssl_cipher" => TemplateField::SslCipher,
+        name => TemplateField::Other(name.to_string()),
+    }
+}
+
+/// Field of a directive of Apache's `LogFormat`, like `%h`, `%>s` or `%{Referer}i`.
+/// Returns the unit of the request time, if it's the directive of the request time,
+/// and the rest of the template.
+fn apache_field(template: &str) -> Result<(TemplateField, Option<f64>, &str), String> {
+    let invalid = || format!("Invalid directive in the format: {}", template);
+
+    // The modifiers of the original or final request (%<s, %>s) are ignored
+    let directive = template[1..].trim_start_matches(['<', '>']);
+    let (argument, directive) = match directive.strip_prefix('{') {
+        Some(directive) => {
+            let end = directive.find('}').ok_or_else(invalid)?;
+            (Some(&directive[..end]), &directive[end + 1..])
+        }
+        None => (None, directive),
+    };
+    let letter = directive
+        .chars()
+        .next()
+        .filter(char::is_ascii_alphabetic)
+        .ok_or_else(invalid)?;
+    let rest = &directive[1..];
+
+    let field = match (letter, argument) {
+        ('v' | 'V', _) => TemplateField::Vhost,
+        ('h' | 'a', _) => TemplateField::Ip,
+        ('l', _) => TemplateField::Identity,
+        ('u', _) => TemplateField::User,
+        ('t', None) => TemplateField::Timestamp,
+        ('r', _) => TemplateField::Request,
+        ('m', _) => TemplateField::Method,
+        ('U', _) => TemplateField::Target,
+        ('H', _) => TemplateField::HttpVersion,
+        ('s', _) => TemplateField::Status,
+        ('b' | 'B', _) => TemplateField::Size,
+        ('i', Some(header)) if header.eq_ignore_ascii_case("referer") => TemplateField::Referer,
+        ('i', Some(header)) if header.eq_ignore_ascii_case("user-agent") => {
+            TemplateField::UserAgent
+        }
+        ('i', Some(header)) if header.eq_ignore_ascii_case("host") => TemplateField::Vhost,
+        ('i', Some(header)) if header.eq_ignore_ascii_case("cookie") => TemplateField::Cookie,
+        ('o', Some(header)) if header.eq_ignore_ascii_case("content-type") => {
+            TemplateField::ContentType
+        }
+        ('i', Some(header)) if header.eq_ignore_ascii_case("x-request-id") => {
+            TemplateField::TraceId
+        }
+        ('e', Some("UNIQUE_ID")) => TemplateField::TraceId,
+        ('X', None) => TemplateField::ConnectionStatus,
+        ('o', Some(header)) if header.eq_ignore_ascii_case("connection") => {
+            TemplateField::Connection
+        }
+        ('i', Some(header)) if header.eq_ignore_ascii_case("x-forwarded-for") => {
+            TemplateField::ForwardedFor
+        }
+        // Bytes received and sent, including the headers (mod_logio)
+        ('I', None) => TemplateField::RequestLength,
+        ('O', None) => TemplateField::BytesSent,
+        ('x', Some("SSL_PROTOCOL")) => TemplateField::SslProtocol,
+        ('x', Some("SSL_CIPHER")) => TemplateField::SslCipher,
+        // The request id of the S3 server access logs
+        ('x', Some("request_id")) => TemplateField::TraceId,
+        // %{remote}p is the port of the client
+        ('p', None | Some("canonical" | "local")) => TemplateField::ServerPort,
+        ('D', _) => return Ok((TemplateField::RequestTime, Some(0.000_001), rest)),
+        ('T', None | Some("s")) => return Ok((TemplateField::RequestTime, Some(1.0), rest)),
+        ('T', Some("ms")) => return Ok((TemplateField::RequestTime, Some(0.001), rest)),
+        ('T', Some("us")) => return Ok((TemplateField::RequestTime, Some(0.000_001), rest)),
+        // Headers and environment variables: %{X-Cache-Status}o -> x_cache_status
+        (_, Some(name)) => TemplateField::Other(name.to_lowercase().replace('-', "_")),
+        _ => TemplateField::Other(template[1..template.len() - rest.len()].to_string()),
+    };
+
+    Ok((field, None, rest))
+}
+
+/// Filter with a list of allowed values and a list of excluded values.
+/// The values are compared case-insensitively.
+#[derive(Clone, Default)]
+pub struct ListFilter {
+    only: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ListFilter {
+    pub fn new(only: Vec<String>, exclude: Vec<String>) -> ListFilter {
+        ListFilter { only, exclude }
+    }
+
+    /// Check whether the value, given as a code and a name, passes the filter
+    fn accepts(&self, code: &Option<String>, name: &Option<String>) -> bool {
+        let matches = |values: &Vec<String>| {
+            values.iter().any(|value| {
+                [code, name].iter().any(|field| {
+                    field
+                        .as_ref()
+                        .is_some_and(|f| f.eq_ignore_ascii_case(value))
+                })
+            })
+        };
+
+        (self.only.is_empty() || matches(&self.only)) && !matches(&self.exclude)
     }
 }
 
 pub struct LogEntry {
     pub line: String,
+    pub trace_id: Option<String>,
+    pub vhost: Option<String>,
+    pub vhost_port: Option<u16>,
+    pub server_port: Option<u16>,
     pub ip: IpAddr,
+    pub x_forwarded_for: Option<String>,
+    pub visitor_id: Option<String>,
+    pub session_id: Option<String>,
     pub identity: Option<String>,
     pub user: Option<String>,
     pub timestamp: DateTime<Utc>,
+    /// The timestamp before truncating it to the time bucket, used as the cursor
+    /// of the incremental imports. It's not stored.
+    pub exact_timestamp: DateTime<Utc>,
+    pub tz_offset_seconds: Option<i32>,
     pub method: HttpMethod,
+    pub scheme: Option<String>,
+    pub request_target: String,
     pub path: String,
+    pub route: Option<String>,
+    pub section: String,
     pub extension: Option<String>,
+    pub is_asset: bool,
     pub query: Option<String>,
+    pub canonical_query: Option<String>,
+    pub fragment: Option<String>,
     pub http_version: HttpVersion,
     pub status_code: u16,
     pub size: usize,
+    /// Bytes of the request, including the request line and the headers
+    pub request_length: Option<u64>,
+    /// Bytes of the response, including the headers
+    pub bytes_sent: Option<u64>,
+    pub request_time: Option<f64>,
+    pub upstream_time: Option<f64>,
+    pub upstream_addr: Option<String>,
+    /// Cache status of nginx (`$upstream_cache_status`)
+    pub cache_status: Option<String>,
+    pub content_type: Option<String>,
+    pub connection_status: Option<String>,
+    pub ssl_protocol: Option<String>,
+    pub ssl_cipher: Option<String>,
     pub referer: Option<Url>,
     pub referer_origin: Option<Origin>,
     pub referer_path: Option<String>,
+    pub referer_has_path: Option<bool>,
     pub referer_query: Option<String>,
     pub user_agent: Option<String>,
 
@@ -136,135 +1141,1018 @@ pub struct LogEntry {
     pub device: Option<String>,
     pub brand: Option<String>,
     pub model: Option<String>,
+    pub ua_canonical: Option<String>,
 
     pub country: Option<String>,
+    pub country_code: Option<String>,
     pub continent: Option<String>,
+    pub continent_code: Option<String>,
     pub asn: Option<String>,
     pub as_name: Option<String>,
     pub as_domain: Option<String>,
+    pub geo_raw: Option<Value>,
+
+    pub ptr_hostname: Option<String>,
+
+    pub extra: Option<Value>,
 }
 
-impl LogEntry {
-    pub fn parse(
-        line: String,
-        services: &mut ParserServices,
-        config: &ParseConfig,
-    ) -> Result<LogEntry, LogError> {
-        let space = Patt::Char(' ');
-        let quote = Patt::Char('"');
-        let bracket = Patt::Char(']');
-        let http = Patt::Str(" HTTP/");
+/// Fields of a line in the combined log format, split but not parsed yet.
+/// Every field is a slice of the original line, so splitting doesn't allocate
+/// and the entry can't outlive the buffer containing the line.
+pub struct RawEntry<'a> {
+    pub line: &'a str,
+    pub vhost: Option<&'a str>,
+    pub ip: &'a str,
+    pub identity: &'a str,
+    pub user: &'a str,
+    pub timestamp: &'a str,
+    pub method: &'a str,
+    pub target: &'a str,
+    pub http_version: &'a str,
+    pub status_code: &'a str,
+    pub size: &'a str,
+    pub referer: &'a str,
+    pub user_agent: &'a str,
+    pub request_time: &'a str,
+    pub upstream_time: &'a str,
+    /// The `Cookie` header, only used to get the session cookie
+    pub cookie: &'a str,
+    pub content_type: &'a str,
+    pub server_port: &'a str,
+    pub trace_id: &'a str,
+    /// `+` (kept alive), `-` (closed) or `X` (aborted)
+    pub connection_status: &'a str,
+    pub x_forwarded_for: &'a str,
+    pub scheme: &'a str,
+    pub request_length: &'a str,
+    pub bytes_sent: &'a str,
+    pub upstream_addr: &'a str,
+    pub cache_status: &'a str,
+    pub ssl_protocol: &'a str,
+    pub ssl_cipher: &'a str,
+    /// Variables of the template without a column, with their names
+    pub variables: Vec<(String, &'a str)>,
+    pub extra: &'a str,
+}
 
-        // Parse ip
-        let (ip, next) =
-            find(0, &line, &space).map_err(|_| LogError::new(&line, "IP not found"))?;
-        let ip: IpAddr = ip.parse().map_err(|_| LogError::new(&line, "Invalid IP"))?;
+/// The fields not found in the line are empty, and the identity, user and size are "-", "-" and "0"
+impl Default for RawEntry<'_> {
+    fn default() -> Self {
+        RawEntry {
+            line: "",
+            vhost: None,
+            ip: "",
+            identity: "-",
+            user: "-",
+            timestamp: "",
+            method: "",
+            target: "",
+            http_version: "",
+            status_code: "",
+            size: "0",
+            referer: "",
+            user_agent: "",
+            request_time: "",
+            upstream_time: "",
+            cookie: "",
+            content_type: "",
+            server_port: "",
+            trace_id: "",
+            connection_status: "",
+            x_forwarded_for: "",
+            scheme: "",
+            request_length: "",
+            bytes_sent: "",
+            upstream_addr: "",
+            cache_status: "",
+            ssl_protocol: "",
+            ssl_cipher: "",
+            variables: Vec::new(),
+            extra: "",
+        }
+    }
+}
 
-        // Parse identity
-        let (identity, next) = find(next + 1, &line, &space)
-            .map_err(|_| LogError::new(&line, "Identity not found"))?;
-        let identity = match identity.as_str() {
-            "-" => None,
-            _ => Some(identity),
+impl<'a> RawEntry<'a> {
+    pub fn parse(line: &'a str, format: &LineFormat) -> Result<RawEntry<'a>, LogError> {
+        let space = Patt::Char(format.delimiter);
+        let quote = if format.escaped {
+            Patt::Quote(format.quote)
+        } else {
+            Patt::Char(format.quote)
         };
+        let bracket = Patt::Char(']');
 
-        // Parse user
-        let (user, next) =
-            find(next + 1, &line, &space).map_err(|_| LogError::new(&line, "User not found"))?;
-        let user = match user.as_str() {
-            "-" => None,
-            _ => Some(user),
+        // Skip the syslog header, if any
+        let start = if format.syslog {
+            syslog_header_length(line)
+        } else {
+            0
         };
 
-        // Parse timestamp
-        let (timestamp, next) = find(next + 2, &line, &bracket)
-            .map_err(|_| LogError::new(&line, "Datetime not found"))?;
-        let timestamp = DateTime::parse_from_str(timestamp.as_str(), "%d/%b/%Y:%H:%M:%S %z")
-            .map(|parsed| parsed.with_timezone(&Utc))
-            .map_err(|_| LogError::new(&line, "Invalid datetime"))?;
-        if timestamp.timestamp_micros() <= config.timestamp {
-            return Err(LogError::new_filtered(&line));
+        if format.base == LogFormat::S3 {
+            let mut raw = RawEntry::from_template(line, start, s3_template(), false)?;
+            for (name, value) in S3_TRAILING_FIELDS.iter().zip(raw.extra.split_whitespace()) {
+                match (*name, value) {
+                    ("host_header", host) if host != "-" => raw.vhost = Some(host),
+                    ("tls_version", version) => raw.ssl_protocol = version,
+                    ("cipher_suite", cipher) => raw.ssl_cipher = cipher,
+                    (name, value) => raw.variables.push((name.to_string(), value)),
+                }
+            }
+            raw.extra = "";
+            return Ok(raw);
         }
 
-        let (request, _) =
-            find(next + 3, &line, &quote).map_err(|_| LogError::new(&line, "Request not found"))?;
-        if request.len() == 0 {
-            return Err(LogError::new(&line, "Empty request"));
+        if let Some(template) = &format.template {
+            return RawEntry::from_template(line, start, template, format.escaped);
         }
 
-        // Parse method
-        let (method, next) = find(next + 3, &line, &space)
-            .map_err(|_| LogError::new(&line, "HTTP method not found"))?;
-        let method = HttpMethod::new(method.as_str())
-            .map_err(|_| LogError::new(&line, "Invalid HTTP method"))?;
+        // The virtual host goes first in the vhost_combined format
+        let (vhost, start) = if format.vhost {
+            let (vhost, next) = find(start, line, &space)
+                .map_err(|_| LogError::new(line, "Virtual host not found"))?;
+            (Some(vhost), next + 1)
+        } else {
+            (None, start)
+        };
 
-        // Parse path, query and extension
-        let (mut fullpath, next) =
-            find(next + 1, &line, &http).map_err(|_| LogError::new(&line, "Path not found"))?;
+        let (ip, next) =
+            find(start, line, &space).map_err(|_| LogError::new(line, "IP not found"))?;
 
-        while fullpath.starts_with("//") {
-            fullpath = fullpath.replacen("//", "/", 1);
-        }
+        let (identity, next) =
+            find(next + 1, line, &space).map_err(|_| LogError::new(line, "Identity not found"))?;
 
-        let url = config
-            .origin
-            .join(&fullpath)
-            .map_err(|_| LogError::new(&line, "Path not valid"))?;
-        if url.host_str() != config.origin.host_str() {
-            return Err(LogError::new(&line, "Path has a different host"));
-        }
-        let path = url.path().to_string();
-        let query = url.query().map(|q| q.to_string());
+        let (user, next) =
+            find(next + 1, line, &space).map_err(|_| LogError::new(line, "User not found"))?;
 
-        let extension = Path::new(&path)
-            .extension()
-            .map(|ext| ext.to_str().unwrap().to_lowercase().to_string());
+        let (timestamp, next) = find(next + 2, line, &bracket)
+            .map_err(|_| LogError::new(line, "Datetime not found"))?;
 
-        // Parse HTTP version
-        let (http_version, next) = find(next + 1, &line, &quote)
-            .map_err(|_| LogError::new(&line, "HTTP version not found"))?;
-        let http_version = HttpVersion::new(http_version.as_str())
-            .map_err(|_| LogError::new(&line, "Invalid HTTP version"))?;
+        let (request, next) =
+            find(next + 3, line, &quote).map_err(|_| LogError::new(line, "Request not found"))?;
+        let (method, target, http_version) = split_request(line, request)?;
 
-        // Parse status code
-        let (status_code, next) = find(next + 2, &line, &space)
-            .map_err(|_| LogError::new(&line, "Status code not found"))?;
-        let status_code: u16 = status_code
-            .parse()
-            .map_err(|_| LogError::new(&line, "Invalid status code"))?;
+        let (status_code, next) = find(next + 2, line, &space)
+            .map_err(|_| LogError::new(line, "Status code not found"))?;
 
-        // Parse size
         let (size, next) =
-            find(next + 1, &line, &space).map_err(|_| LogError::new(&line, "Size not found"))?;
-        let size: usize = size
+            find(next + 1, line, &space).map_err(|_| LogError::new(line, "Size not found"))?;
+
+        let (referer, next) =
+            find(next + 2, line, &quote).map_err(|_| LogError::new(line, "Referer not found"))?;
+
+        // The user agent may run to the end of truncated lines, without the closing quote
+        let (user_agent, extra) = match find(next + 3, line, &quote) {
+            Ok((user_agent, next)) => (user_agent, &line[next + 1..]),
+            Err(_) => {
+                let user_agent = line
+                    .get(next + 3..)
+                    .ok_or_else(|| LogError::new(line, "User agent not found"))?;
+                (user_agent, "")
+            }
+        };
+
+        Ok(RawEntry {
+            line,
+            vhost,
+            ip,
+            identity,
+            user,
+            timestamp,
+            method,
+            target,
+            http_version,
+            status_code,
+            size,
+            referer,
+            user_agent,
+            extra,
+            ..RawEntry::default()
+        })
+    }
+
+    /// Split a line with the fields of a template. The value of every variable runs
+    /// until the text following it in the template, and the rest of the line is `extra`.
+    fn from_template(
+        line: &'a str,
+        start: usize,
+        template: &LogTemplate,
+        escaped: bool,
+    ) -> Result<RawEntry<'a>, LogError> {
+        let mut raw = RawEntry {
+            line,
+            ..RawEntry::default()
+        };
+        let mismatch = || LogError::new(line, "The line doesn't match the format");
+        let mut pos = start;
+
+        for (index, part) in template.parts.iter().enumerate() {
+            let field = match part {
+                TemplatePart::Literal(literal) => {
+                    if !line[pos..].starts_with(literal.as_str()) {
+                        return Err(mismatch());
+                    }
+                    pos += literal.len();
+                    continue;
+                }
+                TemplatePart::Field(field) => field,
+            };
+
+            // The last variable runs to the end of the line
+            let end = match template.parts.get(index + 1) {
+                Some(TemplatePart::Literal(literal)) => {
+                    pos + find_literal(&line[pos..], literal, escaped).ok_or_else(mismatch)?
+                }
+                _ => line.len(),
+            };
+            let value = &line[pos..end];
+            pos = end;
+
+            match field {
+                TemplateField::Vhost => raw.vhost = Some(value),
+                TemplateField::Ip => raw.ip = value,
+                TemplateField::Identity => raw.identity = value,
+                TemplateField::User => raw.user = value,
+                // Apache's %t includes the brackets
+                TemplateField::Timestamp => {
+                    raw.timestamp = value.trim_start_matches('[').trim_end_matches(']')
+                }
+                TemplateField::Request => {
+                    (raw.method, raw.target, raw.http_version) = split_request(line, value)?
+                }
+                TemplateField::Method => raw.method = value,
+                TemplateField::Target => raw.target = value,
+                TemplateField::HttpVersion => raw.http_version = value,
+                TemplateField::Status => raw.status_code = value,
+                // Apache's %b is "-" when no bytes are sent
+                TemplateField::Size if value == "-" => raw.size = "0",
+                TemplateField::Size => raw.size = value,
+                TemplateField::Referer => raw.referer = value,
+                TemplateField::UserAgent => raw.user_agent = value,
+                TemplateField::RequestTime => raw.request_time = value,
+                TemplateField::UpstreamTime => raw.upstream_time = value,
+                TemplateField::Cookie => raw.cookie = value,
+                TemplateField::ContentType => raw.content_type = value,
+                TemplateField::ServerPort => {
+                    if value != "-" && !value.parse::<u16>().is_ok_and(|port| port > 0) {
+                        return Err(LogError::new(line, "Invalid server port"));
+                    }
+                    raw.server_port = value
+                }
+                TemplateField::TraceId => raw.trace_id = value,
+                TemplateField::ConnectionStatus => raw.connection_status = value,
+                // The header is converted to the symbols of Apache's %X
+                TemplateField::Connection => {
+                    raw.connection_status = if value.eq_ignore_ascii_case("keep-alive") {
+                        "+"
+                    } else if value.eq_ignore_ascii_case("close") {
+                        "-"
+                    } else {
+                        ""
+                    }
+                }
+                TemplateField::ForwardedFor => raw.x_forwarded_for = value,
+                TemplateField::Scheme => raw.scheme = value,
+                TemplateField::RequestLength => raw.request_length = value,
+                TemplateField::BytesSent => raw.bytes_sent = value,
+                TemplateField::UpstreamAddr => raw.upstream_addr = value,
+                TemplateField::CacheStatus => raw.cache_status = value,
+                TemplateField::SslProtocol => raw.ssl_protocol = value,
+                TemplateField::SslCipher => raw.ssl_cipher = value,
+                TemplateField::Other(name) => raw.variables.push((name.clone(), value)),
+            }
+        }
+
+        raw.extra = &line[pos..];
+        Ok(raw)
+    }
+
+    /// Name and value of every field, in the order of the line
+    pub fn fields(&self) -> Vec<(&'static str, &'a str)> {
+        let mut fields = Vec::new();
+
+        if let Some(vhost) = self.vhost {
+            fields.push(("vhost", vhost));
+        }
+
+        fields.extend([
+            ("ip", self.ip),
+            ("identity", self.identity),
+            ("user", self.user),
+            ("timestamp", self.timestamp),
+            ("method", self.method),
+            ("target", self.target),
+            ("http_version", self.http_version),
+            ("status_code", self.status_code),
+            ("size", self.size),
+            ("referer", self.referer),
+            ("user_agent", self.user_agent),
+        ]);
+
+        if !self.request_time.is_empty() {
+            fields.push(("request_time", self.request_time));
+        }
+        if !self.upstream_time.is_empty() {
+            fields.push(("upstream_time", self.upstream_time));
+        }
+        if !self.content_type.is_empty() {
+            fields.push(("content_type", self.content_type));
+        }
+        if !self.server_port.is_empty() {
+            fields.push(("server_port", self.server_port));
+        }
+        if !self.trace_id.is_empty() {
+            fields.push(("trace_id", self.trace_id));
+        }
+        if !self.connection_status.is_empty() {
+            fields.push(("connection_status", self.connection_status));
+        }
+        fields.extend(
+            [
+                ("x_forwarded_for", self.x_forwarded_for),
+                ("scheme", self.scheme),
+                ("request_length", self.request_length),
+                ("bytes_sent", self.bytes_sent),
+                ("upstream_addr", self.upstream_addr),
+                ("cache_status", self.cache_status),
+                ("ssl_protocol", self.ssl_protocol),
+                ("ssl_cipher", self.ssl_cipher),
+            ]
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty()),
+        );
+
+        fields.push(("extra", self.extra));
+
+        fields
+    }
+
+    /// Split a line stored in a byte buffer, without copying it.
+    /// The returned fields borrow from the buffer.
+    pub fn from_bytes(line: &'a [u8], format: &LineFormat) -> Result<RawEntry<'a>, LogError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| LogError::new(&String::from_utf8_lossy(line), "Invalid UTF-8"))?;
+
+        RawEntry::parse(line, format)
+    }
+}
+
+impl LogEntry {
+    /// Parse a line. The line is moved to the entry (or the error), without copying it.
+    pub fn parse(
+        line: String,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        let result = LogEntry::parse_str(&line, services, config);
+        with_line(result, line)
+    }
+
+    /// Parse a line from a byte buffer (for example, a network buffer).
+    /// The line is split in place and only the values stored in the
+    /// `LogEntry` are allocated, so the buffer can be reused afterwards.
+    pub fn parse_bytes(
+        line: &[u8],
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| LogError::new(&String::from_utf8_lossy(line), "Invalid UTF-8"))?;
+
+        with_line(
+            LogEntry::parse_str(line, services, config),
+            line.to_string(),
+        )
+    }
+
+    /// Parse a line with the format of the config.
+    /// The entry and the error don't have the line, it's stored by the caller with `with_line`.
+    fn parse_str(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        // The regular expressions are applied before splitting or decoding the line
+        if !config.accepts_line(line) {
+            return Err(LogError::new_filtered(""));
+        }
+
+        match config.format.base {
+            LogFormat::Combined | LogFormat::S3 => {
+                let raw = RawEntry::parse(line, &config.format)?;
+                LogEntry::parse_raw(raw, services, config, None)
+            }
+            LogFormat::Cloudflare => LogEntry::parse_cloudflare(line, services, config),
+            LogFormat::Json => LogEntry::parse_json(line, services, config),
+            LogFormat::CloudFront => LogEntry::parse_cloudfront(line, services, config),
+        }
+    }
+
+    /// Parse a line of Cloudflare Logpush: a JSON object with fields like `ClientIP`,
+    /// `ClientRequestURI` or `EdgeResponseStatus`. The fields are mapped to the ones of
+    /// the combined format, the country detected by Cloudflare (`ClientCountry`) is preferred
+    /// to the IP database, and the rest of fields are stored in `extra`.
+    pub fn from_cloudflare(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        if !config.accepts_line(line) {
+            return Err(LogError::new_filtered(line));
+        }
+
+        with_line(
+            LogEntry::parse_cloudflare(line, services, config),
+            line.to_string(),
+        )
+    }
+
+    fn parse_cloudflare(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        let mut fields: Map<String, Value> =
+            serde_json::from_str(line).map_err(|_| LogError::new("", "Invalid JSON"))?;
+
+        let mut take = |name: &str| match fields.remove(name) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(value)) => Some(value),
+            Some(value) => Some(value.to_string()),
+        };
+        let required = |value: Option<String>, name: &str| {
+            value.ok_or_else(|| LogError::new("", &format!("Missing field {}", name)))
+        };
+
+        let ip = required(take("ClientIP"), "ClientIP")?;
+        let method = required(take("ClientRequestMethod"), "ClientRequestMethod")?;
+        let target = required(take("ClientRequestURI"), "ClientRequestURI")?;
+        let status_code = required(take("EdgeResponseStatus"), "EdgeResponseStatus")?;
+        let timestamp = required(take("EdgeStartTimestamp"), "EdgeStartTimestamp")?;
+        // The original value is kept, so the Unix times have no offset
+        if cloudflare_timestamp(&timestamp).is_none() {
+            return Err(LogError::new("", "Invalid datetime"));
+        }
+
+        // Cloudflare uses HTTP/2 and HTTP/3 (without minor version)
+        let http_version = match take("ClientRequestProtocol").as_deref() {
+            Some("HTTP/2") => "HTTP/2.0".to_string(),
+            Some("HTTP/3") => "HTTP/3.0".to_string(),
+            Some(version) => version.to_string(),
+            None => "HTTP/1.1".to_string(),
+        };
+        let size = take("EdgeResponseBytes").unwrap_or_else(|| "0".to_string());
+        let referer = take("ClientRequestReferer").unwrap_or_default();
+        let user_agent = take("ClientRequestUserAgent").unwrap_or_default();
+        let vhost = take("ClientRequestHost");
+
+        // XX is used for unknown countries
+        let country_code = take("ClientCountry")
+            .filter(|code| !code.is_empty() && !code.eq_ignore_ascii_case("xx"))
+            .map(|code| code.to_uppercase());
+
+        let raw = RawEntry {
+            line,
+            vhost: vhost.as_deref(),
+            ip: &ip,
+            identity: "-",
+            user: "-",
+            timestamp: &timestamp,
+            method: &method,
+            target: &target,
+            http_version: &http_version,
+            status_code: &status_code,
+            size: &size,
+            referer: &referer,
+            user_agent: &user_agent,
+            ..RawEntry::default()
+        };
+
+        let mut entry = LogEntry::parse_raw(raw, services, config, country_code.as_deref())?;
+        if !fields.is_empty() {
+            entry.extra = Some(Value::Object(fields));
+        }
+
+        Ok(entry)
+    }
+
+    /// Parse a line of the CloudFront standard logs: the tab-separated fields of the last
+    /// `#Fields` header (see `ParseConfig::after_header`), with the date and time in UTC. The requested host (`x-host-header`)
+    /// is the virtual host, the `time-taken` is the request time, and the rest of fields
+    /// (like `x-edge-location` or `ssl-protocol`) are stored in `extra`.
+    pub fn from_cloudfront(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        if !config.accepts_line(line) {
+            return Err(LogError::new_filtered(line));
+        }
+
+        with_line(
+            LogEntry::parse_cloudfront(line, services, config),
+            line.to_string(),
+        )
+    }
+
+    fn parse_cloudfront(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        // The header lines (#Version and #Fields) are skipped
+        if line.starts_with('#') {
+            return Err(LogError::new_filtered(""));
+        }
+
+        let mut fields = match &config.format.cloudfront_fields {
+            Some(fields) => fields.split(line),
+            None => return Err(LogError::new("", "The #Fields header was not found")),
+        };
+
+        // "-" is an empty value
+        let required = [
+            "date",
+            "time",
+            "c-ip",
+            "cs-method",
+            "cs-uri-stem",
+            "sc-status",
+        ];
+        if let Some(name) = required
+            .into_iter()
+            .find(|name| fields.get(name).is_none_or(|value| *value == "-"))
+        {
+            return Err(LogError::new("", &format!("Missing field {}", name)));
+        }
+        let mut take = |name: &str| fields.remove(name).filter(|value| *value != "-");
+
+        let date = take("date").unwrap_or_default();
+        let time = take("time").unwrap_or_default();
+        // The times of CloudFront are in UTC, without offset
+        let timestamp = format!("{}T{}", date, time);
+        let ip = take("c-ip").unwrap_or_default();
+        let method = take("cs-method").unwrap_or_default();
+        let path = take("cs-uri-stem").unwrap_or_default();
+        let target = match take("cs-uri-query") {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let status_code = take("sc-status").unwrap_or_default();
+        let http_version = take("cs-protocol-version").unwrap_or("HTTP/1.1");
+        let size = take("sc-bytes").unwrap_or("0");
+        let referer = take("cs(Referer)").unwrap_or_default();
+        // The user agent is percent-encoded (Mozilla/5.0%20(Windows...)
+        let user_agent = decode_path(take("cs(User-Agent)").unwrap_or_default());
+        let request_time = take("time-taken").unwrap_or_default();
+        let content_type = take("sc-content-type").unwrap_or_default();
+        let trace_id = take("x-edge-request-id").unwrap_or_default();
+        let vhost = take("x-host-header");
+        let x_forwarded_for = take("x-forwarded-for").unwrap_or_default();
+        let scheme = take("cs-protocol").unwrap_or_default();
+        let request_length = take("cs-bytes").unwrap_or_default();
+        let ssl_protocol = take("ssl-protocol").unwrap_or_default();
+        let ssl_cipher = take("ssl-cipher").unwrap_or_default();
+
+        // The cookies are percent-encoded and not stored, only used to get the session cookie
+        let cookie = decode_path(take("cs(Cookie)").unwrap_or_default());
+
+        let raw = RawEntry {
+            line,
+            vhost,
+            ip,
+            identity: "-",
+            user: "-",
+            timestamp: &timestamp,
+            method,
+            target: &target,
+            http_version,
+            status_code,
+            size,
+            referer,
+            user_agent: &user_agent,
+            request_time,
+            cookie: &cookie,
+            content_type,
+            trace_id,
+            x_forwarded_for,
+            scheme,
+            request_length,
+            ssl_protocol,
+            ssl_cipher,
+            ..RawEntry::default()
+        };
+
+        let mut entry = LogEntry::parse_raw(raw, services, config, None)?;
+        let extra: Map<String, Value> = fields
+            .into_iter()
+            .filter(|(_, value)| *value != "-")
+            .map(|(name, value)| (name.to_string(), Value::from(value)))
+            .collect();
+        if !extra.is_empty() {
+            entry.extra = Some(Value::Object(extra));
+        }
+
+        Ok(entry)
+    }
+
+    /// Parse a line of a JSON access log: an object with the fields of the request,
+    /// like the ones written by nginx's `log_format` with `escape=json`. The keys are
+    /// configured with `JsonKeys` and the rest of fields are stored in `extra`.
+    /// The request line can be split in the method, the target and the protocol.
+    pub fn from_json(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        if !config.accepts_line(line) {
+            return Err(LogError::new_filtered(line));
+        }
+
+        with_line(
+            LogEntry::parse_json(line, services, config),
+            line.to_string(),
+        )
+    }
+
+    fn parse_json(
+        line: &str,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        let keys = &config.format.json_keys;
+        let mut fields: Map<String, Value> =
+            serde_json::from_str(line).map_err(|_| LogError::new("", "Invalid JSON"))?;
+
+        let mut take = |name: &str| match fields.remove(name) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(value)) => Some(value),
+            Some(value) => Some(value.to_string()),
+        };
+        let required = |value: Option<String>, name: &str| {
+            value.ok_or_else(|| LogError::new("", &format!("Missing field {}", name)))
+        };
+
+        let ip = required(take(&keys.ip), &keys.ip)?;
+        let timestamp = required(take(&keys.timestamp), &keys.timestamp)?;
+        let status_code = required(take(&keys.status_code), &keys.status_code)?;
+        let request = take(&keys.request);
+        let method = take(&keys.method);
+        let target = take(&keys.target);
+        let http_version = take(&keys.http_version);
+        let (method, target, http_version) = match &request {
+            Some(request) => {
+                let (method, target, http_version) = split_request(line, request)?;
+                (
+                    method.to_string(),
+                    target.to_string(),
+                    http_version.to_string(),
+                )
+            }
+            None => (
+                required(method, &keys.method)?,
+                required(target, &keys.target)?,
+                http_version.unwrap_or_else(|| "HTTP/1.1".to_string()),
+            ),
+        };
+        let user = take(&keys.user).unwrap_or_else(|| "-".to_string());
+        let size = take(&keys.size).unwrap_or_else(|| "0".to_string());
+        let referer = take(&keys.referer).unwrap_or_default();
+        let user_agent = take(&keys.user_agent).unwrap_or_default();
+        let request_time = take(&keys.request_time).unwrap_or_default();
+        let upstream_time = take(&keys.upstream_time).unwrap_or_default();
+        let vhost = take(&keys.vhost);
+
+        let raw = RawEntry {
+            line,
+            vhost: vhost.as_deref(),
+            ip: &ip,
+            identity: "-",
+            user: &user,
+            timestamp: &timestamp,
+            method: &method,
+            target: &target,
+            http_version: &http_version,
+            status_code: &status_code,
+            size: &size,
+            referer: &referer,
+            user_agent: &user_agent,
+            request_time: &request_time,
+            upstream_time: &upstream_time,
+            ..RawEntry::default()
+        };
+
+        let mut entry = LogEntry::parse_raw(raw, services, config, None)?;
+        if !fields.is_empty() {
+            entry.extra = Some(Value::Object(fields));
+        }
+
+        Ok(entry)
+    }
+
+    pub fn from_raw(
+        raw: RawEntry,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+    ) -> Result<LogEntry, LogError> {
+        let line = raw.line;
+        if !config.accepts_line(line) {
+            return Err(LogError::new_filtered(line));
+        }
+
+        with_line(
+            LogEntry::parse_raw(raw, services, config, None),
+            line.to_string(),
+        )
+    }
+
+    /// Parse the fields of a line. The country code, if it's provided
+    /// (for example, by a CDN), is preferred to the IP database.
+    /// The entry and the errors don't have the line, it's stored by the caller.
+    fn parse_raw(
+        raw: RawEntry,
+        services: &mut ParserServices,
+        config: &ParseConfig,
+        country_code: Option<&str>,
+    ) -> Result<LogEntry, LogError> {
+        let invalid = |error: &str| LogError::new("", error);
+        let filtered = || LogError::new_filtered("");
+
+        // Parse virtual host and port: Example.com:443 -> example.com, 443
+        let (vhost, vhost_port) = match raw.vhost {
+            None | Some("-") => (None, None),
+            Some(vhost) => match split_port(vhost) {
+                (host, Some(port)) => {
+                    let port: u16 = port
+                        .parse()
+                        .map_err(|_| invalid("Invalid virtual host port"))?;
+                    (Some(host.to_lowercase()), Some(port))
+                }
+                (host, None) => (Some(host.to_lowercase()), None),
+            },
+        };
+
+        // The server port is validated when the line is split
+        let server_port: Option<u16> = raw.server_port.parse().ok();
+
+        // Parse ip, discarding the zone of the IPv6 link-local addresses (fe80::1%eth0)
+        let ip: IpAddr = match raw.ip.split_once('%') {
+            Some((address, _zone)) if address.contains(':') => address,
+            _ => raw.ip,
+        }
+        .parse()
+        .map_err(|_| invalid("Invalid IP"))?;
+
+        // Parse identity
+        let identity = match raw.identity {
+            "-" => None,
+            identity => Some(identity.to_string()),
+        };
+
+        // Parse user
+        let user = match raw.user {
+            "-" => None,
+            user => Some(user.to_string()),
+        };
+
+        // Parse timestamp
+        let (timestamp, has_offset) =
+            parse_timestamp(raw.timestamp).ok_or_else(|| invalid("Invalid datetime"))?;
+        let tz_offset_seconds = has_offset.then(|| timestamp.offset().local_minus_utc());
+        let exact_timestamp = timestamp.with_timezone(&Utc);
+        let cursor = vhost
+            .as_ref()
+            .and_then(|vhost| config.vhost_timestamps.get(vhost))
+            .unwrap_or(&config.timestamp);
+        if exact_timestamp.timestamp_micros() <= *cursor {
+            return Err(filtered());
+        }
+
+        // The time bucket is applied after the cursor check, so the new requests
+        // of the last bucket imported are not skipped
+        let timestamp = match config.time_bucket {
+            Some(bucket) => bucket.truncate(exact_timestamp),
+            None => exact_timestamp,
+        };
+
+        // Parse method
+        let method = HttpMethod::new(raw.method).map_err(|_| invalid("Invalid HTTP method"))?;
+
+        // Parse path, query and extension
+        let mut fullpath = config.unescape(raw.target).into_owned();
+
+        while fullpath.starts_with("//") {
+            fullpath = fullpath.replacen("//", "/", 1);
+        }
+
+        let mut url = config
+            .origin
+            .join(&fullpath)
+            .map_err(|_| invalid("Path not valid"))?;
+        if !same_host(&url, &config.origin)
+            && !config.origins.iter().any(|origin| same_host(&url, origin))
+        {
+            return Err(invalid("Path has a different host"));
+        }
+        config.redact_query(&mut url);
+        let path = decode_path(url.path());
+        let path = if config.lowercase_path {
+            path.to_lowercase()
+        } else {
+            path
+        };
+        if !config.accepts_path(&path) {
+            return Err(filtered());
+        }
+        let query = url.query().map(|q| config.truncate_query(q));
+        let canonical_query = if config.canonical_query {
+            url.query()
+                .map(|_| config.truncate_query(&canonical_query(&url)))
+        } else {
+            None
+        };
+
+        let route = config.routes.as_ref().map(|routes| routes.route(&path));
+
+        // Requests don't usually include the fragment, but scanners can send it
+        let fragment = url.fragment().map(|f| f.to_string());
+
+        // First segment of the path: /blog/post-1 -> blog
+        let section = path
+            .split('/')
+            .find(|segment| !segment.is_empty())
+            .unwrap_or("(root)")
+            .to_string();
+
+        let extension = Path::new(&path)
+            .extension()
+            .map(|ext| ext.to_str().unwrap().to_lowercase().to_string());
+
+        let is_asset = extension
+            .as_ref()
+            .is_some_and(|ext| config.asset_extensions.contains(ext));
+
+        // Parse HTTP version
+        let http_version =
+            HttpVersion::new(raw.http_version).map_err(|_| invalid("Invalid HTTP version"))?;
+
+        // Parse status code
+        let status_code: u16 = config
+            .strip_separators(raw.status_code)
+            .parse()
+            .map_err(|_| invalid("Invalid status code"))?;
+        if !config.accepts_status(status_code) {
+            return Err(filtered());
+        }
+
+        // Parse size
+        let size: usize = config
+            .strip_separators(raw.size)
             .parse()
-            .map_err(|_| LogError::new(&line, "Invalid size"))?;
+            .map_err(|_| invalid("Invalid size"))?;
+
+        // Parse the times of the template, in seconds
+        let time_unit = config
+            .format
+            .fields_template()
+            .map_or(1.0, |template| template.request_time_unit);
+        let request_time = parse_seconds(raw.request_time, time_unit);
+        let upstream_time = parse_seconds(raw.upstream_time, 1.0);
+        let content_type = optional_field(raw.content_type);
+        let trace_id = optional_field(raw.trace_id);
+        let x_forwarded_for = optional_field(raw.x_forwarded_for);
+        let scheme = optional_field(raw.scheme).map(|scheme| scheme.to_lowercase());
+        let upstream_addr = optional_field(raw.upstream_addr);
+        let cache_status = optional_field(raw.cache_status);
+        let ssl_protocol = optional_field(raw.ssl_protocol);
+        let ssl_cipher = optional_field(raw.ssl_cipher);
+
+        // Sizes with the headers ("-" if unknown)
+        let request_length = optional_field(raw.request_length)
+            .map(|length| length.parse())
+            .transpose()
+            .map_err(|_| invalid("Invalid request length"))?;
+        let bytes_sent = optional_field(raw.bytes_sent)
+            .map(|bytes| bytes.parse())
+            .transpose()
+            .map_err(|_| invalid("Invalid bytes sent"))?;
+        let connection_status = match raw.connection_status {
+            status @ ("+" | "-" | "X") => Some(status.to_string()),
+            _ => None,
+        };
 
         // Parse referer
-        let (referer, next) =
-            find(next + 2, &line, &quote).map_err(|_| LogError::new(&line, "Referer not found"))?;
-        let referer = Url::parse(&referer).ok();
+        let mut referer = Url::parse(&config.unescape(raw.referer)).ok();
+        if let Some(referer) = referer.as_mut() {
+            config.redact_query(referer);
+        }
         let (referer_origin, referer_path, referer_query) = referer.as_ref().map_or_else(
             || (None, None, None),
             |url| {
                 (
                     Some(url.origin()),
                     Some(url.path().to_string()),
-                    url.query().map(|q| q.to_string()),
+                    url.query().map(|q| config.truncate_query(q)),
                 )
             },
         );
 
+        // Origin-only referers (sent with strict referrer policies) have the root path
+        let referer_has_path = referer_path
+            .as_deref()
+            .map(|path| !path.is_empty() && path != "/");
+
         // Parse user agent
-        let (user_agent, _) = find(next + 3, &line, &quote)
-            .map_err(|_| LogError::new(&line, "User agent not found"))?;
+        let user_agent = if raw.user_agent.is_empty() {
+            None
+        } else {
+            Some(config.unescape(raw.user_agent).into_owned())
+        };
+
+        // Pseudonymous id of the visitor, stable during the day
+        let visitor_id = config
+            .visitor_salt
+            .as_ref()
+            .map(|salt| visitor_id(salt, &ip, &timestamp, user_agent.as_deref().unwrap_or("")));
+
+        // Only the session cookie is stored, not the whole header
+        let session_id = config
+            .session_cookie
+            .as_ref()
+            .and_then(|name| cookie_value(raw.cookie, name))
+            .map(String::from);
 
-        let user_agent = if user_agent.is_empty() {
+        // Capture the variables of the template without a column ("-" is an empty value),
+        // and any trailing fields not included in the format (under `_trailing` if there are
+        // variables)
+        let trailing = split_fields(raw.extra, config.format.quote, config.format.delimiter);
+        let extra = if !raw.variables.is_empty() {
+            let mut variables: Map<String, Value> = raw
+                .variables
+                .iter()
+                .map(|(name, value)| {
+                    let value = match *value {
+                        "-" => Value::Null,
+                        value => Value::from(config.unescape(value).into_owned()),
+                    };
+                    (name.clone(), value)
+                })
+                .collect();
+            if !trailing.is_empty() {
+                variables.insert("_trailing".to_string(), Value::from(trailing));
+            }
+            Some(Value::Object(variables))
+        } else if trailing.is_empty() {
             None
         } else {
-            Some(user_agent)
+            Some(Value::from(trailing))
+        };
+
+        // Parse geolocation
+        let (country, country_code, continent, continent_code, asn, as_name, as_domain, geo_raw) = {
+            let geolocation = services.get_geolocation(&ip);
+
+            // The names and the continent of the database are kept if it has the same country
+            let (country, country_code, continent, continent_code) = match country_code {
+                Some(code)
+                    if !geolocation
+                        .country_code
+                        .as_deref()
+                        .is_some_and(|c| c.eq_ignore_ascii_case(code)) =>
+                {
+                    (None, Some(code.to_string()), None, None)
+                }
+                _ => (
+                    geolocation.country.clone(),
+                    geolocation.country_code.clone(),
+                    geolocation.continent.clone(),
+                    geolocation.continent_code.clone(),
+                ),
+            };
+
+            if !config.countries.accepts(&country_code, &country)
+                || !config.continents.accepts(&continent_code, &continent)
+            {
+                return Err(filtered());
+            }
+
+            (
+                country,
+                country_code,
+                continent,
+                continent_code,
+                geolocation.asn.clone(),
+                geolocation.as_name.clone(),
+                geolocation.as_domain.clone(),
+                geolocation.raw.clone(),
+            )
         };
 
+        // Parse reverse DNS
+        let ptr_hostname = services.get_hostname(&ip);
+
         // Parse agent data
         let (
             browser,
@@ -305,34 +2193,55 @@ impl LogEntry {
                 None, None, None, None, None, None, None, None, None, None, None, None, None,
             ));
 
-        // Parse geolocation
-        let (country, continent, asn, as_name, as_domain) = {
-            let geolocation = services.get_geolocation(&ip);
-            (
-                geolocation.country.clone(),
-                geolocation.continent.clone(),
-                geolocation.asn.clone(),
-                geolocation.as_name.clone(),
-                geolocation.as_domain.clone(),
-            )
+        let ua_canonical = if config.normalize_ua {
+            ua_canonical(&browser, &browser_major, &os, &device)
+        } else {
+            None
         };
 
         Ok(LogEntry {
-            line,
+            line: String::new(),
+            trace_id,
+            vhost,
+            vhost_port,
+            server_port,
             ip,
+            x_forwarded_for,
+            visitor_id,
+            session_id,
             identity,
             user,
             timestamp,
+            exact_timestamp,
+            tz_offset_seconds,
             method,
+            scheme,
+            request_target: raw.target.to_string(),
             path,
+            route,
+            section,
             extension,
+            is_asset,
             query,
+            canonical_query,
+            fragment,
             http_version,
             status_code,
             size,
+            request_length,
+            bytes_sent,
+            request_time,
+            upstream_time,
+            upstream_addr,
+            cache_status,
+            content_type,
+            connection_status,
+            ssl_protocol,
+            ssl_cipher,
             referer,
             referer_origin,
             referer_path,
+            referer_has_path,
             referer_query,
             user_agent,
             browser,
@@ -348,13 +2257,188 @@ impl LogEntry {
             device,
             brand,
             model,
+            ua_canonical,
             country,
+            country_code,
             continent,
+            continent_code,
             asn,
             as_name,
             as_domain,
+            geo_raw,
+            ptr_hostname,
+            extra,
         })
     }
+
+    /// Convert the entry to a JSON object, with the same keys as the columns of the database
+    pub fn to_json(&self) -> Value {
+        let mut json = Map::new();
+        let mut set = |key: &str, value: Value| {
+            json.insert(key.to_string(), value);
+        };
+
+        set("trace_id", self.trace_id.clone().into());
+        set("vhost", self.vhost.clone().into());
+        set("vhost_port", self.vhost_port.into());
+        set("server_port", self.server_port.into());
+        set("ip", self.ip.to_string().into());
+        set("x_forwarded_for", self.x_forwarded_for.clone().into());
+        set("visitor_id", self.visitor_id.clone().into());
+        set("session_id", self.session_id.clone().into());
+        set("identity", self.identity.clone().into());
+        set("user", self.user.clone().into());
+        set("timestamp", self.timestamp.to_rfc3339().into());
+        set("tz_offset_seconds", self.tz_offset_seconds.into());
+        set("method", self.method.to_string().into());
+        set("scheme", self.scheme.clone().into());
+        set("request_target", self.request_target.clone().into());
+        set("path", self.path.clone().into());
+        set("route", self.route.clone().into());
+        set("section", self.section.clone().into());
+        set("extension", self.extension.clone().into());
+        set("is_asset", self.is_asset.into());
+        set("query", self.query.clone().into());
+        set("canonical_query", self.canonical_query.clone().into());
+        set("fragment", self.fragment.clone().into());
+        set("http_version", self.http_version.to_string().into());
+        set("status_code", self.status_code.into());
+        set("size", self.size.into());
+        set("request_length", self.request_length.into());
+        set("bytes_sent", self.bytes_sent.into());
+        set("request_time", self.request_time.into());
+        set("upstream_time", self.upstream_time.into());
+        set("upstream_addr", self.upstream_addr.clone().into());
+        set("cache_status", self.cache_status.clone().into());
+        set("content_type", self.content_type.clone().into());
+        set("connection_status", self.connection_status.clone().into());
+        set("ssl_protocol", self.ssl_protocol.clone().into());
+        set("ssl_cipher", self.ssl_cipher.clone().into());
+        set("referer", self.referer.as_ref().map(Url::to_string).into());
+        set(
+            "referer_origin",
+            self.referer_origin
+                .as_ref()
+                .map(Origin::unicode_serialization)
+                .into(),
+        );
+        set("referer_path", self.referer_path.clone().into());
+        set("referer_has_path", self.referer_has_path.into());
+        set("referer_query", self.referer_query.clone().into());
+        set("user_agent", self.user_agent.clone().into());
+        set("browser", self.browser.clone().into());
+        set("browser_major", self.browser_major.into());
+        set("browser_minor", self.browser_minor.into());
+        set("browser_patch", self.browser_patch.into());
+        set("browser_patch_minor", self.browser_patch_minor.into());
+        set("os", self.os.clone().into());
+        set("os_major", self.os_major.into());
+        set("os_minor", self.os_minor.into());
+        set("os_patch", self.os_patch.into());
+        set("os_patch_minor", self.os_patch_minor.into());
+        set("device", self.device.clone().into());
+        set("brand", self.brand.clone().into());
+        set("model", self.model.clone().into());
+        set("ua_canonical", self.ua_canonical.clone().into());
+        set("country", self.country.clone().into());
+        set("country_code", self.country_code.clone().into());
+        set("continent", self.continent.clone().into());
+        set("continent_code", self.continent_code.clone().into());
+        set("asn", self.asn.clone().into());
+        set("as_name", self.as_name.clone().into());
+        set("as_domain", self.as_domain.clone().into());
+        set("geo_raw", self.geo_raw.clone().unwrap_or(Value::Null));
+        set("ptr_hostname", self.ptr_hostname.clone().into());
+        set("extra", self.extra.clone().unwrap_or(Value::Null));
+
+        Value::Object(json)
+    }
+}
+
+/// Parse a line and return the entry as a JSON object (see `LogEntry::to_json`)
+pub fn parse_to_json(
+    line: String,
+    services: &mut ParserServices,
+    config: &ParseConfig,
+) -> Result<Value, LogError> {
+    LogEntry::parse(line, services, config).map(|entry| entry.to_json())
+}
+
+/// Parse the lines of a log, returning every entry (or error) with its line number.
+/// The line numbers are 1-based, like in text editors, and count all lines read,
+/// including the filtered and invalid ones, so they can be used to resume the processing.
+pub fn parse_lines<'s, 'a, I: Iterator<Item = String>>(
+    lines: I,
+    services: &'s mut ParserServices<'a>,
+    config: &'s ParseConfig,
+) -> ParsedLines<'s, 'a, I> {
+    ParsedLines {
+        lines: mark_duplicates(lines, config.dedup_consecutive),
+        services,
+        config: Cow::Borrowed(config),
+    }
+}
+
+/// Iterator returned by `parse_lines`
+pub struct ParsedLines<'s, 'a, I> {
+    lines: MarkedLines<I>,
+    services: &'s mut ParserServices<'a>,
+    /// The config is copied when a `#Fields` header changes the fields of the next lines
+    config: Cow<'s, ParseConfig>,
+}
+
+impl<'s, 'a, I: Iterator<Item = String>> Iterator for ParsedLines<'s, 'a, I> {
+    type Item = (usize, Result<LogEntry, LogError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (number, line, duplicated) = self.lines.next()?;
+
+        if let Some(config) = self.config.after_header(&line) {
+            self.config = Cow::Owned(config);
+        }
+
+        if duplicated {
+            let error = LogError::new_filtered("");
+            return Some((number, with_line(Err(error), line)));
+        }
+
+        Some((number, LogEntry::parse(line, self.services, &self.config)))
+    }
+}
+
+/// Return every line of a log with its number (1-based, like `parse_lines`) and whether
+/// it's identical to the previous line. With `dedup` disabled, no line is marked.
+pub fn mark_duplicates<I: Iterator<Item = String>>(lines: I, dedup: bool) -> MarkedLines<I> {
+    MarkedLines {
+        lines: lines.enumerate(),
+        dedup,
+        previous: None,
+    }
+}
+
+/// Iterator returned by `mark_duplicates`
+pub struct MarkedLines<I> {
+    lines: std::iter::Enumerate<I>,
+    dedup: bool,
+    previous: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Iterator for MarkedLines<I> {
+    type Item = (usize, String, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, line) = self.lines.next()?;
+        let mut duplicated = false;
+
+        if self.dedup {
+            duplicated = self.previous.as_ref() == Some(&line);
+            if !duplicated {
+                self.previous = Some(line.clone());
+            }
+        }
+
+        Some((index + 1, line, duplicated))
+    }
 }
 
 #[derive(Debug)]
@@ -389,86 +2473,904 @@ impl LogError {
     pub fn is_filtered(&self) -> bool {
         self.filter
     }
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+    pub fn reason(&self) -> &str {
+        &self.error
+    }
 }
 
-enum Patt<'a> {
+enum Patt {
     Char(char),
-    Str(&'a str),
+    /// A quote not escaped with a backslash
+    Quote(char),
+}
+
+/// Optional value of a field, empty or "-" if it's missing
+fn optional_field(value: &str) -> Option<String> {
+    match value {
+        "" | "-" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+/// Value of a cookie of a `Cookie` header (name1=value1; name2=value2), if it's not empty
+fn cookie_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|value| !value.is_empty())
+}
+
+/// Store the line in the entry or the error, moving it instead of copying it
+fn with_line(result: Result<LogEntry, LogError>, line: String) -> Result<LogEntry, LogError> {
+    match result {
+        Ok(entry) => Ok(LogEntry { line, ..entry }),
+        Err(error) => Err(LogError { line, ..error }),
+    }
+}
+
+/// Split the host and the port of a virtual host: example.com:443 -> example.com, 443
+/// The IPv6 addresses are in brackets, with or without port: [::1]:8080, [::1]
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    let start = if host.starts_with('[') {
+        host.find(']').map_or(0, |end| end + 1)
+    } else {
+        0
+    };
+
+    match host[start..].rfind(':') {
+        Some(index) => (&host[..start + index], Some(&host[start + index + 1..])),
+        None => (host, None),
+    }
+}
+
+/// Split the request line (method, target and HTTP version)
+fn split_request<'a>(
+    line: &str,
+    request: &'a str,
+) -> Result<(&'a str, &'a str, &'a str), LogError> {
+    if request.is_empty() {
+        return Err(LogError::new(line, "Empty request"));
+    }
+
+    // Malformed request lines, like "GET/ HTTP/1.1" or "GET HTTP/1.1", are usually sent by scanners
+    let (method, target) = request
+        .split_once(' ')
+        .filter(|(method, _)| method.chars().all(|c| c.is_ascii_alphabetic()))
+        .ok_or_else(|| LogError::new(line, "Malformed request line"))?;
+
+    if target.is_empty() || target.starts_with(' ') || target.starts_with("HTTP/") {
+        return Err(LogError::new(line, "Malformed request line (missing path)"));
+    }
+
+    let (target, http_version) = target
+        .find(" HTTP/")
+        .map(|pos| (&target[..pos], &target[pos + 1..]))
+        .ok_or_else(|| LogError::new(line, "HTTP version not found"))?;
+
+    Ok((method, target, http_version))
 }
 
-fn find(start: usize, line: &String, pattern: &Patt) -> Result<(String, usize), ParseError> {
+/// Parse a time in seconds, like nginx's `$request_time` (`0.012`), multiplied by the unit.
+/// nginx joins the times of several upstreams (`0.002, 0.004 : 0.001`), that are added up.
+fn parse_seconds(value: &str, unit: f64) -> Option<f64> {
+    let times: Vec<f64> = value
+        .split([',', ':'])
+        .filter_map(|time| time.trim().parse::<f64>().ok())
+        .filter(|time| time.is_finite())
+        .collect();
+
+    if times.is_empty() {
+        None
+    } else {
+        Some(times.iter().sum::<f64>() * unit)
+    }
+}
+
+/// Position of a text of the template in the rest of the line.
+/// With escaped values, the text is not found after a backslash (`\"` doesn't close a quoted value).
+fn find_literal(rest: &str, literal: &str, escaped: bool) -> Option<usize> {
+    rest.match_indices(literal).map(|(pos, _)| pos).find(|pos| {
+        !escaped
+            || rest[..*pos]
+                .chars()
+                .rev()
+                .take_while(|c| *c == '\\')
+                .count()
+                % 2
+                == 0
+    })
+}
+
+fn find<'a>(start: usize, line: &'a str, pattern: &Patt) -> Result<(&'a str, usize), ParseError> {
+    let rest = line.get(start..).ok_or_else(ParseError::new)?;
     let pos = match pattern {
-        Patt::Char(c) => line[start..].find(*c),
-        Patt::Str(s) => line[start..].find(*s),
+        Patt::Char(c) => rest.find(*c),
+        Patt::Quote(c) => {
+            let mut escaped = false;
+            rest.char_indices()
+                .find(|(_, ch)| {
+                    let found = ch == c && !escaped;
+                    escaped = *ch == '\\' && !escaped;
+                    found
+                })
+                .map(|(pos, _)| pos)
+        }
     };
 
-    if let Some(pos) = pos {
-        let end = start + pos;
+    if let Some(pos) = pos {
+        let end = start + pos;
+
+        Ok((&line[start..end], end))
+    } else {
+        Err(ParseError::new())
+    }
+}
+
+/// Time spent in every stage of the import (used by --profile)
+#[derive(Default, Clone)]
+pub struct Profile {
+    pub reading: Duration,
+    pub parsing: Duration,
+    pub user_agent: Duration,
+    pub geolocation: Duration,
+    pub appending: Duration,
+}
+
+/// Detect the origin of a site from a sample of its log lines.
+/// The hosts are collected from the virtual host field, the absolute request
+/// targets and the referers, and the most common one is returned.
+pub fn detect_origin(lines: impl Iterator<Item = String>, format: &LineFormat) -> Option<String> {
+    let mut origins: HashMap<String, usize> = HashMap::new();
+    let mut format = Cow::Borrowed(format);
+
+    for line in lines {
+        if let Some(header) = format.after_header(&line) {
+            format = Cow::Owned(header);
+            continue;
+        }
+
+        // Cloudflare includes the host and the scheme of every request
+        if format.base == LogFormat::Cloudflare {
+            if let Ok(Value::Object(fields)) = serde_json::from_str(&line) {
+                if let Some(Value::String(host)) = fields.get("ClientRequestHost") {
+                    let scheme = match fields.get("ClientRequestScheme") {
+                        Some(Value::String(scheme)) => scheme.as_str(),
+                        _ => "https",
+                    };
+                    let origin = format!("{}://{}", scheme, host).to_lowercase();
+                    *origins.entry(origin).or_insert(0) += 1;
+                }
+            }
+            continue;
+        }
+
+        // CloudFront logs the host requested by the viewer
+        if format.base == LogFormat::CloudFront {
+            let host = format
+                .cloudfront_fields
+                .as_ref()
+                .and_then(|fields| fields.get(&line, "x-host-header"))
+                .filter(|host| *host != "-");
+            if let Some(host) = host {
+                let origin = format!("https://{}", host).to_lowercase();
+                *origins.entry(origin).or_insert(0) += 1;
+            }
+            continue;
+        }
+
+        // The JSON logs may include the host of the request
+        if format.base == LogFormat::Json {
+            if let Ok(Value::Object(fields)) = serde_json::from_str(&line) {
+                if let Some(Value::String(host)) = fields.get(&format.json_keys.vhost) {
+                    let origin = format!("https://{}", host).to_lowercase();
+                    *origins.entry(origin).or_insert(0) += 1;
+                }
+            }
+            continue;
+        }
+
+        let raw = match RawEntry::parse(&line, &format) {
+            Ok(raw) => raw,
+            Err(_) => continue,
+        };
+
+        let mut candidates = Vec::new();
+
+        if let Some(vhost) = raw.vhost {
+            let (host, port) = split_port(vhost);
+            let scheme = if port == Some("80") { "http" } else { "https" };
+            candidates.push(format!("{}://{}", scheme, host));
+        }
+
+        for url in [raw.target, raw.referer] {
+            if let Ok(url) = Url::parse(url) {
+                if url.has_host() && url.scheme().starts_with("http") {
+                    candidates.push(url.origin().ascii_serialization());
+                }
+            }
+        }
+
+        for origin in candidates {
+            *origins.entry(origin.to_lowercase()).or_insert(0) += 1;
+        }
+    }
+
+    origins
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+        .map(|(origin, _)| origin)
+}
+
+/// Get the timestamp of the first valid entry of a log, used to sort the log files
+/// imported together (like the rotated `access.log.1`, `access.log.2`, etc).
+pub fn first_timestamp(
+    lines: impl Iterator<Item = String>,
+    format: &LineFormat,
+) -> Option<DateTime<Utc>> {
+    let mut format = Cow::Borrowed(format);
+
+    lines
+        .filter_map(|line| {
+            if let Some(header) = format.after_header(&line) {
+                format = Cow::Owned(header);
+                return None;
+            }
+
+            let timestamp = match format.base {
+                LogFormat::Cloudflare => {
+                    let fields: Map<String, Value> = serde_json::from_str(&line).ok()?;
+                    match fields.get("EdgeStartTimestamp")? {
+                        Value::String(timestamp) => cloudflare_timestamp(timestamp),
+                        timestamp => cloudflare_timestamp(&timestamp.to_string()),
+                    }
+                }
+                LogFormat::Json => {
+                    let fields: Map<String, Value> = serde_json::from_str(&line).ok()?;
+                    match fields.get(&format.json_keys.timestamp)? {
+                        Value::String(timestamp) => parse_timestamp(timestamp),
+                        timestamp => parse_timestamp(&timestamp.to_string()),
+                    }
+                    .map(|(timestamp, _)| timestamp)
+                }
+                LogFormat::CloudFront => {
+                    let fields = format.cloudfront_fields.as_ref()?;
+                    let (date, time) = (fields.get(&line, "date")?, fields.get(&line, "time")?);
+                    parse_timestamp(&format!("{}T{}", date, time)).map(|(timestamp, _)| timestamp)
+                }
+                LogFormat::Combined | LogFormat::S3 => {
+                    parse_timestamp(RawEntry::parse(&line, &format).ok()?.timestamp)
+                        .map(|(timestamp, _)| timestamp)
+                }
+            };
+            timestamp.map(|timestamp| timestamp.with_timezone(&Utc))
+        })
+        .next()
+}
+
+/// Parse the query of a URL into a map of decoded keys and values.
+/// The same rules are applied to the request and referer URLs:
+/// - The query is decoded as `application/x-www-form-urlencoded`,
+///   so both `+` and `%20` are decoded as a space.
+/// - Invalid UTF-8 sequences are replaced with `U+FFFD`.
+/// - Parameters without value (`?foo`) have an empty value.
+/// - If a key is repeated, the last value is kept.
+pub fn parse_query(url: &Url) -> HashMap<String, String> {
+    url.query()
+        .map_or_else(HashMap::new, |query| query_pairs(query).collect())
+}
+
+/// Decoded pairs of a query string, in order and with the repeated keys,
+/// following the same rules as `parse_query`.
+pub fn query_pairs(query: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+}
+
+/// Query of a URL with the parameters of `parse_query` sorted by key and encoded
+/// in the same way, so `?b=2&a=1` and `?a=1&b=2` have the same canonical query (`a=1&b=2`).
+pub fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = parse_query(url).into_iter().collect();
+    pairs.sort();
+
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish()
+}
+
+/// Key of a user agent with the browser, its major version, the OS and the device
+/// (`Chrome 120 on Windows`, `Mobile Safari 17 on iOS (iPhone)`), to group the user agents
+/// that only differ in minor versions. The unknown values (`Other`) are omitted.
+fn ua_canonical(
+    browser: &Option<String>,
+    browser_major: &Option<u16>,
+    os: &Option<String>,
+    device: &Option<String>,
+) -> Option<String> {
+    let known = |value: &Option<String>| value.clone().filter(|value| value != "Other");
+    let mut key = known(browser)?;
+
+    if let Some(major) = browser_major {
+        key.push_str(&format!(" {}", major));
+    }
+    if let Some(os) = known(os) {
+        key.push_str(&format!(" on {}", os));
+    }
+    if let Some(device) = known(device) {
+        key.push_str(&format!(" ({})", device));
+    }
+
+    Some(key)
+}
+
+/// Pseudonymous id of a visitor: a hash of the salt, the day of the request,
+/// the anonymized IP and the user agent. The id is the same for all requests
+/// of a visitor during a day (in UTC) and changes the next day, so the visits
+/// can be counted without storing the IP and the visitors can't be tracked for long.
+pub fn visitor_id(salt: &str, ip: &IpAddr, timestamp: &DateTime<Utc>, user_agent: &str) -> String {
+    let day = timestamp.format("%Y-%m-%d").to_string();
+    let ip = anonymize_ip(ip).to_string();
+    let mut hasher = blake3::Hasher::new();
+
+    for value in [salt, &day, &ip, user_agent] {
+        hasher.update(value.as_bytes());
+        hasher.update(b"\0");
+    }
+
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+/// Remove the last byte of IPv4 and the last 80 bits of IPv6 addresses
+fn anonymize_ip(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[6..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+/// Decode the escape sequences of a field, as written by nginx (`\x22`)
+/// and JSON (`\"`, `\\`, `\/`, `\n`, `\u00e9`). Invalid sequences are kept as is,
+/// and the bytes that are not valid UTF-8 are replaced with `U+FFFD`.
+pub fn unescape(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(pos) = rest.find('\\') {
+        bytes.extend_from_slice(&rest.as_bytes()[..pos]);
+        rest = &rest[pos + 1..];
+
+        let hex = |len: usize| {
+            rest.get(1..len + 1)
+                .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        };
+
+        let (decoded, len): (Option<char>, usize) = match rest.chars().next() {
+            Some('x') => match hex(2) {
+                Some(byte) => {
+                    // A byte, that can be part of a UTF-8 sequence
+                    bytes.push(byte as u8);
+                    rest = &rest[3..];
+                    continue;
+                }
+                None => (None, 0),
+            },
+            Some('u') => (hex(4).and_then(char::from_u32), 5),
+            Some('"') => (Some('"'), 1),
+            Some('\\') => (Some('\\'), 1),
+            Some('/') => (Some('/'), 1),
+            Some('n') => (Some('\n'), 1),
+            Some('r') => (Some('\r'), 1),
+            Some('t') => (Some('\t'), 1),
+            Some('b') => (Some('\u{8}'), 1),
+            Some('f') => (Some('\u{c}'), 1),
+            _ => (None, 0),
+        };
+
+        match decoded {
+            Some(c) => {
+                let mut buffer = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                rest = &rest[len..];
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+
+    bytes.extend_from_slice(rest.as_bytes());
+    Cow::Owned(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Check whether the URL of a request (absolute in the request line of proxies)
+/// belongs to the origin. Only the hosts are compared: the scheme and the port are
+/// ignored (`http://example.com` and `https://example.com:443` are the same host),
+/// as well as the final dot of the fully qualified names (`example.com.`).
+fn same_host(url: &Url, origin: &Url) -> bool {
+    let host = |url: &Url| {
+        url.host_str()
+            .map(|host| host.trim_end_matches('.').to_string())
+    };
+    host(url) == host(origin)
+}
+
+/// Decode the percent-encoded bytes of a path (`/caf%C3%A9` -> `/café`).
+/// The bytes that are not valid UTF-8 (like `%E9`, an `é` in Latin-1) are
+/// replaced with `U+FFFD`, so the decoding is lossy.
+fn decode_path(path: &str) -> String {
+    if !path.contains('%') {
+        return path.to_string();
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = path
+            .get(index + 1..index + 3)
+            .filter(|hex| bytes[index] == b'%' && hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse the time of the request: `05/Jan/2024:13:55:36 +0100`.
+/// Some servers use the name of the timezone instead of the offset
+/// (`05/Jan/2024:13:55:36 GMT` or `05/Jan/2024:13:55:36 Europe/Madrid`),
+/// that is converted to the offset of the zone at that time.
+/// Unix times (like nginx's `$msec`) are also accepted, see `epoch_timestamp`,
+/// and RFC 3339 times (like nginx's `$time_iso8601`: `2024-01-05T13:55:36+01:00`).
+/// Returns the time and whether it has an offset or a timezone: the Unix times and
+/// the ISO 8601 times without offset (like the CloudFront ones: `2024-01-05T13:55:36`)
+/// are in UTC, but the original timezone is unknown.
+fn parse_timestamp(value: &str) -> Option<(DateTime<FixedOffset>, bool)> {
+    if let Ok(timestamp) = DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z") {
+        return Some((timestamp, true));
+    }
+
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some((timestamp, true));
+    }
+
+    if let Some(timestamp) = epoch_timestamp(value) {
+        return Some((timestamp, false));
+    }
+
+    if let Ok(timestamp) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some((timestamp.and_utc().fixed_offset(), false));
+    }
+
+    let (datetime, zone) = value.rsplit_once(' ')?;
+    let datetime = NaiveDateTime::parse_from_str(datetime, "%d/%b/%Y:%H:%M:%S").ok()?;
+    let zone: Tz = match zone {
+        "Z" => Tz::UTC,
+        zone => zone.parse().ok()?,
+    };
+
+    // Times repeated by daylight saving changes use the first offset
+    zone.from_local_datetime(&datetime)
+        .earliest()
+        .map(|timestamp| (timestamp.fixed_offset(), true))
+}
+
+/// Parse the `EdgeStartTimestamp` of Cloudflare, in any of the formats of Logpush:
+/// RFC 3339 (`2024-01-05T13:55:36Z`) or Unix time in seconds, milliseconds or nanoseconds.
+fn cloudflare_timestamp(value: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp);
+    }
+
+    epoch_timestamp(value)
+}
+
+/// Parse a Unix time in UTC. The unit is detected by the magnitude of the number,
+/// so the dates between 1973 and 5138 are supported in any unit:
+/// - Seconds, with optional decimals: `1704462936` or `1704462936.123`
+/// - Milliseconds: `1704462936123`
+/// - Microseconds: `1704462936123456`
+/// - Nanoseconds: `1704462936123456789`
+fn epoch_timestamp(value: &str) -> Option<DateTime<FixedOffset>> {
+    let (integer, decimals) = value.split_once('.').unwrap_or((value, ""));
+    if integer.is_empty()
+        || !integer.bytes().all(|b| b.is_ascii_digit())
+        || !decimals.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let number: i64 = integer.parse().ok()?;
+    let timestamp = match number {
+        n if n >= 100_000_000_000_000_000 => DateTime::from_timestamp_nanos(n),
+        n if n >= 100_000_000_000_000 => DateTime::from_timestamp_micros(n)?,
+        n if n >= 100_000_000_000 => DateTime::from_timestamp_millis(n)?,
+        n => {
+            // Decimals of the seconds, up to nanoseconds
+            let nanos = format!("{:0<9}", &decimals[..decimals.len().min(9)]);
+            DateTime::from_timestamp(n, nanos.parse().ok()?)?
+        }
+    };
+
+    Some(timestamp.fixed_offset())
+}
+
+/// Length of the syslog header at the start of the line, or 0 if there's no header.
+/// The PRI part (`<34>`) is optional, because it's not saved to files by most syslog daemons.
+/// - RFC 3164: `<34>Jan  5 13:55:36 host nginx: `
+/// - RFC 5424: `<34>1 2025-01-05T13:55:36.003Z host nginx 1234 - - `
+fn syslog_header_length(line: &str) -> usize {
+    let mut rest = line;
+
+    // PRI: <0-191>
+    if let Some((pri, after)) = rest.strip_prefix('<').and_then(|r| r.split_once('>')) {
+        if !pri.is_empty() && pri.len() <= 3 && pri.chars().all(|c| c.is_ascii_digit()) {
+            rest = after;
+        }
+    }
+
+    let header = match rest.strip_prefix("1 ") {
+        // VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA
+        Some(after) => skip_fields(after, 5).and_then(skip_structured_data),
+        // TIMESTAMP (Mmm dd hh:mm:ss) HOSTNAME TAG:
+        None => {
+            let is_month = rest
+                .get(..3)
+                .is_some_and(|m| m.chars().all(|c| c.is_ascii_alphabetic()));
+            if is_month {
+                skip_fields(rest, 4).map(|after| match after.split_once(' ') {
+                    Some((tag, msg)) if tag.ends_with(':') => msg,
+                    _ => after,
+                })
+            } else {
+                None
+            }
+        }
+    };
+
+    header.map_or(0, |msg| line.len() - msg.len())
+}
+
+/// Skip a number of fields separated by one or more spaces
+fn skip_fields(value: &str, count: usize) -> Option<&str> {
+    let mut rest = value.trim_start_matches(' ');
+
+    for _ in 0..count {
+        let (_, after) = rest.split_once(' ')?;
+        rest = after.trim_start_matches(' ');
+    }
+
+    Some(rest)
+}
+
+/// Skip the structured data of RFC 5424: "-" or one or more "[id key="value"]"
+fn skip_structured_data(value: &str) -> Option<&str> {
+    if let Some(after) = value.strip_prefix("- ") {
+        return Some(after);
+    }
+
+    let mut rest = value;
+    while rest.starts_with('[') {
+        // The values can contain escaped brackets: \]
+        let mut escaped = false;
+        let end = rest.char_indices().find(|(_, c)| {
+            let found = *c == ']' && !escaped;
+            escaped = *c == '\\' && !escaped;
+            found
+        })?;
+        rest = &rest[end.0 + 1..];
+    }
+
+    rest.strip_prefix(' ')
+}
+
+/// Split the fields separated by the delimiter, keeping quoted fields together
+fn split_fields(fields: &str, quote: char, delimiter: char) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut rest = fields.trim_start();
+
+    while !rest.is_empty() {
+        let (field, next) = match rest.strip_prefix(quote) {
+            Some(quoted) => match quoted.find(quote) {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match rest.find(delimiter) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            },
+        };
+
+        result.push(field.to_string());
+        rest = next.trim_start();
+    }
+
+    result
+}
+
+/// Bytes of the IP database, borrowed from the binary or shared between services,
+/// so creating several services doesn't copy the database
+enum IpDatabase {
+    #[cfg(feature = "ipinfo")]
+    Embedded(&'static [u8]),
+    Shared(Arc<[u8]>),
+}
+
+impl AsRef<[u8]> for IpDatabase {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            #[cfg(feature = "ipinfo")]
+            IpDatabase::Embedded(bytes) => bytes,
+            IpDatabase::Shared(bytes) => bytes,
+        }
+    }
+}
+
+pub struct ParserServices<'a> {
+    cache: Arc<dyn LookupCache>,
+    agents_parser: Extractor<'a>,
+    browser_only: bool,
+    geo_raw: bool,
+    geo_provider: Option<Box<dyn GeoProvider>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    reverse_dns: Option<reverse_dns::ReverseDns>,
+    profile: Option<Profile>,
+}
+
+impl<'a> ParserServices<'a> {
+    pub fn new() -> ParserServices<'a> {
+        let regexes_bytes = include_bytes!("../resources/regexes.yaml");
+        let regexes: Regexes = serde_yaml::from_slice(regexes_bytes).unwrap();
+        let agents_parser = Extractor::try_from(regexes).unwrap();
+
+        // IPinfo Lite (Free) -> https://ipinfo.io/dashboard/downloads
+        #[cfg(feature = "ipinfo")]
+        let geo_provider: Option<Box<dyn GeoProvider>> = {
+            let ipinfo = include_bytes!("../resources/ipinfo_lite.mmdb");
+            let reader = Reader::from_source(IpDatabase::Embedded(ipinfo)).unwrap();
+            Some(Box::new(MmdbProvider { reader }))
+        };
+        #[cfg(not(feature = "ipinfo"))]
+        let geo_provider = None;
+
+        ParserServices {
+            cache: Arc::new(MemoryCache::default()),
+            agents_parser,
+            browser_only: false,
+            geo_raw: false,
+            geo_provider,
+            #[cfg(not(target_arch = "wasm32"))]
+            reverse_dns: None,
+            profile: None,
+        }
+    }
+
+    /// Geolocate the IPs with this MaxMind database (with the IPinfo Lite fields)
+    /// instead of the embedded one. Without the `ipinfo` feature, this is the only
+    /// way to enable geolocation.
+    pub fn set_ip_database(&mut self, database: Vec<u8>) -> Result<(), String> {
+        self.set_shared_ip_database(database.into())
+    }
+
+    /// The same as `set_ip_database`, but the database is shared with other services
+    /// (for example, the services of a pool of parsers) instead of copied.
+    pub fn set_shared_ip_database(&mut self, database: Arc<[u8]>) -> Result<(), String> {
+        let reader =
+            Reader::from_source(IpDatabase::Shared(database)).map_err(|err| err.to_string())?;
+        self.set_geo_provider(Box::new(MmdbProvider { reader }));
+        Ok(())
+    }
+
+    /// Geolocate the IPs with a custom provider (another database format, a web service,
+    /// fixed values for tests...) instead of the MaxMind database
+    pub fn set_geo_provider(&mut self, provider: Box<dyn GeoProvider>) {
+        self.geo_provider = Some(provider);
+        self.cache.clear_geolocations();
+    }
+
+    /// Cache the user agents and the geolocations in this cache, shared with other services
+    /// (for example, the services of a pool of parsers), so every value is looked up only once.
+    /// All the services sharing a cache must be configured with the same options.
+    pub fn set_shared_cache(&mut self, cache: Arc<dyn LookupCache>) {
+        self.cache = cache;
+    }
+
+    /// Resolve the hostname of the IPs with reverse DNS lookups
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn enable_reverse_dns(&mut self, timeout: Duration) {
+        self.reverse_dns = Some(reverse_dns::ReverseDns::new(timeout));
+    }
+
+    /// Detect only the browser of the user agents, skipping the slower detection of the
+    /// operating system and the device (their fields are `None`)
+    pub fn enable_browser_only(&mut self) {
+        self.browser_only = true;
+        self.cache.clear_agents();
+    }
+
+    /// Keep the whole record of the geolocation of every IP (`GeoLocation::raw`),
+    /// for the databases with more fields than the IPinfo Lite ones
+    pub fn enable_geo_raw(&mut self) {
+        self.geo_raw = true;
+        self.cache.clear_geolocations();
+    }
+
+    /// Start measuring the time spent in user agent and geolocation lookups
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn get_agent(&mut self, user_agent: &str) -> Arc<Agent> {
+        if let Some(agent) = self.cache.get_agent(user_agent) {
+            return agent;
+        }
+
+        let start = self.profile.as_ref().map(|_| Instant::now());
+        let mut agent = if self.browser_only {
+            Agent::from(self.agents_parser.ua.extract(user_agent), None, None)
+        } else {
+            let (ua, os, device) = self.agents_parser.extract(user_agent);
+            Agent::from(ua, os, device)
+        };
+
+        // Special case Mozlila (https://trunc.org/learning/the-mozlila-user-agent-bot)
+        if user_agent.contains("Mozlila") && !self.browser_only {
+            agent.device = Some(String::from("Spider"));
+        }
+
+        let agent = Arc::new(agent);
+        self.cache.set_agent(user_agent, agent.clone());
+
+        if let (Some(profile), Some(start)) = (self.profile.as_mut(), start) {
+            profile.user_agent += start.elapsed();
+        }
+
+        agent
+    }
+
+    pub fn get_geolocation(&mut self, ip: &IpAddr) -> Arc<GeoLocation> {
+        if let Some(geolocation) = self.cache.get_geolocation(ip) {
+            return geolocation;
+        }
+
+        let start = self.profile.as_ref().map(|_| Instant::now());
+        let geolocation = match &self.geo_provider {
+            Some(provider) if self.geo_raw => GeoLocation {
+                raw: provider.lookup_raw(ip),
+                ..provider.lookup(ip)
+            },
+            Some(provider) => provider.lookup(ip),
+            None => GeoLocation::new(),
+        };
+
+        let geolocation = Arc::new(geolocation);
+        self.cache.set_geolocation(ip, geolocation.clone());
+
+        if let (Some(profile), Some(start)) = (self.profile.as_mut(), start) {
+            profile.geolocation += start.elapsed();
+        }
+
+        geolocation
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn get_hostname(&mut self, ip: &IpAddr) -> Option<String> {
+        self.reverse_dns.as_ref()?.lookup(*ip)
+    }
 
-        Ok((line[start..end].to_string(), end))
-    } else {
-        Err(ParseError::new())
+    /// Reverse DNS lookups are not available in WebAssembly
+    #[cfg(target_arch = "wasm32")]
+    pub fn get_hostname(&mut self, _ip: &IpAddr) -> Option<String> {
+        None
     }
 }
 
-pub struct ParserServices<'a> {
-    geolocations: HashMap<String, GeoLocation>,
-    agents: HashMap<String, Agent>,
-    agents_parser: Extractor<'a>,
-    ip_reader: Reader<Vec<u8>>,
-}
+/// Cache of the user agents and the geolocations of the IPs of `ParserServices`.
+/// It can be used by several threads at the same time, so a cache can be shared
+/// by the services of every worker (see `ParserServices::set_shared_cache`).
+pub trait LookupCache: Send + Sync {
+    fn get_agent(&self, user_agent: &str) -> Option<Arc<Agent>>;
+    fn set_agent(&self, user_agent: &str, agent: Arc<Agent>);
+    fn get_geolocation(&self, ip: &IpAddr) -> Option<Arc<GeoLocation>>;
+    fn set_geolocation(&self, ip: &IpAddr, geolocation: Arc<GeoLocation>);
 
-impl<'a> ParserServices<'a> {
-    pub fn new() -> ParserServices<'a> {
-        let regexes_bytes = include_bytes!("../resources/regexes.yaml");
-        let regexes: Regexes = serde_yaml::from_slice(regexes_bytes).unwrap();
-        let agents_parser = Extractor::try_from(regexes).unwrap();
+    /// Remove the user agents, after changing how they are detected
+    fn clear_agents(&self);
 
-        // IPinfo Lite (Free) -> https://ipinfo.io/dashboard/downloads
-        let ipinfo = include_bytes!("../resources/ipinfo_lite.mmdb").to_vec();
-        let ip_reader = Reader::from_source(ipinfo).unwrap();
+    /// Remove the geolocations, after changing the IP database
+    fn clear_geolocations(&self);
+}
 
-        ParserServices {
-            geolocations: HashMap::new(),
-            agents: HashMap::new(),
-            agents_parser,
-            ip_reader,
-        }
+/// The default cache, that keeps every value in memory
+#[derive(Default)]
+pub struct MemoryCache {
+    agents: RwLock<HashMap<String, Arc<Agent>>>,
+    geolocations: RwLock<HashMap<IpAddr, Arc<GeoLocation>>>,
+}
+
+impl LookupCache for MemoryCache {
+    fn get_agent(&self, user_agent: &str) -> Option<Arc<Agent>> {
+        self.agents.read().unwrap().get(user_agent).cloned()
     }
 
-    pub fn get_agent(&mut self, user_agent: &str) -> &Agent {
-        if !self.agents.contains_key(user_agent) {
-            let (ua, os, device) = self.agents_parser.extract(user_agent);
-            let mut agent = Agent::from(ua, os, device);
+    fn set_agent(&self, user_agent: &str, agent: Arc<Agent>) {
+        self.agents
+            .write()
+            .unwrap()
+            .insert(user_agent.to_string(), agent);
+    }
 
-            // Special case Mozlila (https://trunc.org/learning/the-mozlila-user-agent-bot)
-            if user_agent.contains("Mozlila") {
-                agent.device = Some(String::from("Spider"));
-            }
+    fn get_geolocation(&self, ip: &IpAddr) -> Option<Arc<GeoLocation>> {
+        self.geolocations.read().unwrap().get(ip).cloned()
+    }
 
-            self.agents.insert(user_agent.to_string(), agent);
-        }
+    fn set_geolocation(&self, ip: &IpAddr, geolocation: Arc<GeoLocation>) {
+        self.geolocations.write().unwrap().insert(*ip, geolocation);
+    }
 
-        self.agents.get(user_agent).unwrap()
+    fn clear_agents(&self) {
+        self.agents.write().unwrap().clear();
     }
 
-    pub fn get_geolocation(&mut self, ip: &IpAddr) -> &GeoLocation {
-        let key = ip.to_string();
+    fn clear_geolocations(&self) {
+        self.geolocations.write().unwrap().clear();
+    }
+}
 
-        if !self.geolocations.contains_key(&key) {
-            let geolocation = self.parse_geolocation(ip);
-            self.geolocations.insert(key.clone(), geolocation);
-        }
+/// Source of the geolocation of the IPs. The results are cached by `ParserServices`,
+/// so every IP is looked up only once.
+pub trait GeoProvider: Send {
+    fn lookup(&self, ip: &IpAddr) -> GeoLocation;
 
-        self.geolocations.get(&key).unwrap()
+    /// The whole record of the IP as JSON, if the provider has one
+    fn lookup_raw(&self, _ip: &IpAddr) -> Option<Value> {
+        None
     }
+}
+
+/// Geolocation with a MaxMind database with the IPinfo Lite fields
+struct MmdbProvider {
+    reader: Reader<IpDatabase>,
+}
 
-    fn parse_geolocation(&self, ip: &IpAddr) -> GeoLocation {
+impl GeoProvider for MmdbProvider {
+    fn lookup(&self, ip: &IpAddr) -> GeoLocation {
         let mut geolocation = GeoLocation::new();
-        let info = self.ip_reader.lookup::<IpInfo>(ip.clone());
+        let info = self.reader.lookup::<IpInfo>(ip.clone());
         if let Ok(info) = info {
             geolocation.continent = info.continent;
+            geolocation.continent_code = info.continent_code;
             geolocation.country = info.country;
+            geolocation.country_code = info.country_code;
             geolocation.asn = info.asn;
             geolocation.as_name = info.as_name;
             geolocation.as_domain = info.as_domain;
@@ -476,17 +3378,120 @@ impl<'a> ParserServices<'a> {
 
         return geolocation;
     }
+
+    fn lookup_raw(&self, ip: &IpAddr) -> Option<Value> {
+        self.reader.lookup::<Value>(*ip).ok()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod reverse_dns {
+    use std::collections::{HashMap, HashSet};
+    use std::net::IpAddr;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // Number of lookups running at the same time
+    const LOOKUP_WORKERS: usize = 8;
+
+    // Maximum number of lookups waiting for a free worker
+    const MAX_QUEUED_LOOKUPS: usize = 32;
+
+    type Job = (IpAddr, mpsc::Sender<Option<String>>);
+
+    #[derive(Default)]
+    struct Lookups {
+        // Finished lookups: the hostname, or None if the IP has no PTR record
+        finished: HashMap<IpAddr, Option<String>>,
+        // Queued or running lookups
+        pending: HashSet<IpAddr>,
+    }
+
+    /// Reverse DNS resolver, with a pool of workers and a timeout for every lookup.
+    /// The lookups that time out keep running, and their result is used the next time
+    /// the IP is found. Only the finished lookups are cached.
+    pub struct ReverseDns {
+        timeout: Duration,
+        jobs: mpsc::SyncSender<Job>,
+        lookups: Arc<Mutex<Lookups>>,
+    }
+
+    impl ReverseDns {
+        pub fn new(timeout: Duration) -> ReverseDns {
+            let (jobs, receiver) = mpsc::sync_channel::<Job>(MAX_QUEUED_LOOKUPS);
+            let receiver = Arc::new(Mutex::new(receiver));
+            let lookups = Arc::new(Mutex::new(Lookups::default()));
+
+            // The workers stop when the resolver (the sender of the jobs) is dropped
+            for _ in 0..LOOKUP_WORKERS {
+                let receiver = receiver.clone();
+                let lookups = lookups.clone();
+
+                thread::spawn(move || loop {
+                    // The lock is released after receiving the job
+                    let Ok((ip, sender)) = receiver.lock().unwrap().recv() else {
+                        break;
+                    };
+
+                    // getnameinfo returns the IP itself if there's no PTR record
+                    let hostname = dns_lookup::lookup_addr(&ip).ok().map(|hostname| {
+                        Some(hostname).filter(|hostname| *hostname != ip.to_string())
+                    });
+
+                    let mut lookups = lookups.lock().unwrap();
+                    lookups.pending.remove(&ip);
+                    // The failed lookups (no answer of the DNS server) are tried again
+                    if let Some(hostname) = &hostname {
+                        lookups.finished.insert(ip, hostname.clone());
+                    }
+                    let _ = sender.send(hostname.flatten());
+                });
+            }
+
+            ReverseDns {
+                timeout,
+                jobs,
+                lookups,
+            }
+        }
+
+        pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+            let (sender, receiver) = mpsc::channel();
+
+            {
+                let mut lookups = self.lookups.lock().unwrap();
+                if let Some(hostname) = lookups.finished.get(&ip) {
+                    return hostname.clone();
+                }
+                // The IP is still being resolved for a previous request that timed out
+                if lookups.pending.contains(&ip) {
+                    return None;
+                }
+                // Don't wait if all the workers are busy and the queue is full
+                if self.jobs.try_send((ip, sender)).is_err() {
+                    return None;
+                }
+                lookups.pending.insert(ip);
+            }
+
+            receiver.recv_timeout(self.timeout).ok().flatten()
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct IpInfo {
     continent: Option<String>,
+    continent_code: Option<String>,
     country: Option<String>,
+    country_code: Option<String>,
     asn: Option<String>,
     as_name: Option<String>,
     as_domain: Option<String>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Agent {
     pub browser: Option<String>,
     pub browser_major: Option<u16>,
@@ -561,22 +3566,779 @@ impl Agent {
     }
 }
 
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct GeoLocation {
     pub country: Option<String>,
+    pub country_code: Option<String>,
     pub continent: Option<String>,
+    pub continent_code: Option<String>,
     pub asn: Option<String>,
     pub as_name: Option<String>,
     pub as_domain: Option<String>,
+    pub raw: Option<Value>,
 }
 
 impl GeoLocation {
     pub fn new() -> GeoLocation {
         GeoLocation {
             country: None,
+            country_code: None,
             continent: None,
+            continent_code: None,
             asn: None,
             as_name: None,
             as_domain: None,
+            raw: None,
+        }
+    }
+}
+
+/// Entry points to parse lines from JavaScript, with `wasm-bindgen`.
+/// The reverse DNS lookups are not available and the geolocation requires the
+/// `ipinfo` feature or a database passed with `set_ip_database_wasm`.
+#[cfg(feature = "wasm")]
+mod wasm {
+    use crate::{parse_to_json, ParseConfig, ParserServices};
+    use std::cell::RefCell;
+    use url::Url;
+    use wasm_bindgen::prelude::wasm_bindgen;
+
+    thread_local! {
+        // The services are created once, because loading the user agent regexes is slow
+        static SERVICES: RefCell<ParserServices<'static>> = RefCell::new(ParserServices::new());
+    }
+
+    /// Parse a line of the combined log format and return the entry as a JSON string
+    #[wasm_bindgen]
+    pub fn parse_line_wasm(line: String, origin: &str) -> Result<String, String> {
+        Url::parse(origin).map_err(|_| format!("Invalid origin {}", origin))?;
+        let config = ParseConfig::new(0, origin);
+
+        SERVICES.with(|services| {
+            parse_to_json(line, &mut services.borrow_mut(), &config)
+                .map(|json| json.to_string())
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    /// Load a MaxMind database (with the IPinfo Lite fields) to geolocate the IPs
+    #[wasm_bindgen]
+    pub fn set_ip_database_wasm(database: Vec<u8>) -> Result<(), String> {
+        SERVICES.with(|services| services.borrow_mut().set_ip_database(database))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with_template(template: &str, line: &str) -> Result<LogEntry, LogError> {
+        let format = LineFormat {
+            template: Some(Arc::new(LogTemplate::new(template).unwrap())),
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+
+        LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config)
+    }
+
+    #[test]
+    fn content_type_from_nginx_template() {
+        let entry = parse_with_template(
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent "$sent_http_content_type""#,
+            r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET /about HTTP/1.1" 200 512 "text/html; charset=utf-8""#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            entry.content_type.as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert!(entry.extra.is_none());
+    }
+
+    #[test]
+    fn content_type_from_apache_template() {
+        let entry = parse_with_template(
+            r#"%h %l %u %t "%r" %>s %b "%{Content-Type}o""#,
+            r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /logo.png HTTP/1.1" 200 2048 "image/png""#,
+        )
+        .unwrap();
+
+        assert_eq!(entry.content_type.as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn missing_content_type() {
+        let entry = parse_with_template(
+            r#"%h %l %u %t "%r" %>s %b "%{Content-Type}o""#,
+            r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 304 - "-""#,
+        )
+        .unwrap();
+
+        assert_eq!(entry.content_type, None);
+    }
+
+    #[test]
+    fn cursor_is_checked_before_the_time_bucket() {
+        let (cursor, _) = parse_timestamp("10/Oct/2024:13:55:36 +0000").unwrap();
+        let config = ParseConfig::new(cursor.timestamp_micros(), "https://example.com")
+            .with_time_bucket(TimeBucket::Hour);
+        let mut services = ParserServices::new();
+        let line = |time: &str| {
+            format!(
+                r#"1.2.3.4 - - [10/Oct/2024:{} +0000] "GET / HTTP/1.1" 200 512 "-" "-""#,
+                time
+            )
+        };
+
+        let entry = LogEntry::parse(line("13:58:00"), &mut services, &config).unwrap();
+        assert_eq!(entry.timestamp.to_rfc3339(), "2024-10-10T13:00:00+00:00");
+        assert_eq!(
+            entry.exact_timestamp.to_rfc3339(),
+            "2024-10-10T13:58:00+00:00"
+        );
+
+        let result = LogEntry::parse(line("13:50:00"), &mut services, &config);
+        assert!(result.is_err_and(|error| error.is_filtered()));
+    }
+
+    #[test]
+    fn server_port_is_validated() {
+        let template = r#"%h %p %t "%r" %>s %b"#;
+        let line = |port: &str| {
+            format!(
+                r#"1.2.3.4 {} [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512"#,
+                port
+            )
+        };
+
+        let entry = parse_with_template(template, &line("8443")).unwrap();
+        assert_eq!(entry.server_port, Some(8443));
+
+        let entry = parse_with_template(template, &line("-")).unwrap();
+        assert_eq!(entry.server_port, None);
+
+        for port in ["0", "70000", "http"] {
+            let result = parse_with_template(template, &line(port));
+            assert!(result.is_err_and(|error| error.reason() == "Invalid server port"));
+        }
+    }
+
+    #[test]
+    fn trace_id_from_nginx_template() {
+        let entry = parse_with_template(
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent $request_id"#,
+            r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 4f3c2a1b9e8d7c6b"#,
+        )
+        .unwrap();
+
+        assert_eq!(entry.trace_id.as_deref(), Some("4f3c2a1b9e8d7c6b"));
+        assert!(entry.extra.is_none());
+    }
+
+    #[test]
+    fn trace_id_from_apache_template() {
+        let template = r#"%h %l %u %t "%r" %>s %b "%{X-Request-Id}i""#;
+        let line = |id: &str| {
+            format!(
+                r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 "{}""#,
+                id
+            )
+        };
+
+        let entry = parse_with_template(template, &line("req-123")).unwrap();
+        assert_eq!(entry.trace_id.as_deref(), Some("req-123"));
+
+        let entry = parse_with_template(template, &line("-")).unwrap();
+        assert_eq!(entry.trace_id, None);
+    }
+
+    #[test]
+    fn connection_status_from_templates() {
+        let line = |status: &str| {
+            format!(
+                r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 {}"#,
+                status
+            )
+        };
+        let apache = r#"%h %t "%r" %>s %b %X"#;
+        let nginx = r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent $sent_http_connection"#;
+
+        for (template, value, expected) in [
+            (apache, "+", Some("+")),
+            (apache, "-", Some("-")),
+            (apache, "X", Some("X")),
+            (nginx, "keep-alive", Some("+")),
+            (nginx, "close", Some("-")),
+            (nginx, "-", None),
+        ] {
+            let entry = parse_with_template(template, &line(value)).unwrap();
+            assert_eq!(entry.connection_status.as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn consecutive_duplicates_are_marked() {
+        let lines = ["a", "a", "b", "a", "a"].map(String::from);
+
+        let marked: Vec<(usize, bool)> = mark_duplicates(lines.clone().into_iter(), true)
+            .map(|(number, _, duplicated)| (number, duplicated))
+            .collect();
+        assert_eq!(
+            marked,
+            [(1, false), (2, true), (3, false), (4, false), (5, true)]
+        );
+
+        assert!(mark_duplicates(lines.into_iter(), false).all(|(_, _, duplicated)| !duplicated));
+    }
+
+    #[test]
+    fn known_variables_have_columns() {
+        let template = r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent $scheme "$http_x_forwarded_for" $request_length $bytes_sent $upstream_addr $upstream_cache_status $ssl_protocol $ssl_cipher $connection_requests"#;
+        let line = r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 HTTPS "10.0.0.1, 10.0.0.2" 420 780 127.0.0.1:8080 HIT TLSv1.3 TLS_AES_128_GCM_SHA256 3"#;
+
+        let entry = parse_with_template(template, line).unwrap();
+        assert_eq!(entry.scheme.as_deref(), Some("https"));
+        assert_eq!(entry.x_forwarded_for.as_deref(), Some("10.0.0.1, 10.0.0.2"));
+        assert_eq!(entry.request_length, Some(420));
+        assert_eq!(entry.bytes_sent, Some(780));
+        assert_eq!(entry.upstream_addr.as_deref(), Some("127.0.0.1:8080"));
+        assert_eq!(entry.cache_status.as_deref(), Some("HIT"));
+        assert_eq!(entry.ssl_protocol.as_deref(), Some("TLSv1.3"));
+        assert_eq!(entry.ssl_cipher.as_deref(), Some("TLS_AES_128_GCM_SHA256"));
+
+        // Only the unknown variables are stored in extra
+        let extra = entry.extra.unwrap();
+        assert_eq!(extra, serde_json::json!({ "connection_requests": "3" }));
+    }
+
+    #[test]
+    fn known_apache_directives_have_columns() {
+        let template = r#"%h %t "%r" %>s %b %I %O "%{X-Forwarded-For}i" %{SSL_PROTOCOL}x"#;
+        let line = |received: &str| {
+            format!(
+                r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 {} 780 "-" -"#,
+                received
+            )
+        };
+
+        let entry = parse_with_template(template, &line("420")).unwrap();
+        assert_eq!(entry.request_length, Some(420));
+        assert_eq!(entry.bytes_sent, Some(780));
+        assert_eq!(entry.x_forwarded_for, None);
+        assert_eq!(entry.ssl_protocol, None);
+        assert!(entry.extra.is_none());
+
+        let result = parse_with_template(template, &line("many"));
+        assert!(result.is_err_and(|error| error.reason() == "Invalid request length"));
+    }
+
+    #[test]
+    fn cloudfront_fields_from_the_header() {
+        let format = LineFormat {
+            base: LogFormat::CloudFront,
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format.clone());
+        let lines = [
+            "#Version: 1.0",
+            "#Fields: date time c-ip cs-method cs-uri-stem sc-status x-edge-location",
+            "2024-10-10\t13:55:36\t1.2.3.4\tGET\t/about\t200\tMAD53-P1",
+            "#Fields: time date sc-status cs-method c-ip cs-uri-stem",
+            "13:56:00\t2024-10-10\t404\tPOST\t5.6.7.8\t/missing",
+        ]
+        .map(String::from);
+
+        let mut services = ParserServices::new();
+        let entries: Vec<LogEntry> = parse_lines(lines.clone().into_iter(), &mut services, &config)
+            .filter_map(|(_, result)| result.ok())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "/about");
+        assert_eq!(entries[0].tz_offset_seconds, None);
+        assert_eq!(
+            entries[0].extra,
+            Some(serde_json::json!({ "x-edge-location": "MAD53-P1" }))
+        );
+        assert_eq!(entries[1].path, "/missing");
+        assert_eq!(entries[1].status_code, 404);
+        assert_eq!(entries[1].ip.to_string(), "5.6.7.8");
+
+        let timestamp = first_timestamp(lines.into_iter(), &format).unwrap();
+        assert_eq!(timestamp.to_rfc3339(), "2024-10-10T13:55:36+00:00");
+    }
+
+    #[test]
+    fn cloudfront_lines_require_the_header() {
+        let format = LineFormat {
+            base: LogFormat::CloudFront,
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+        let line = "2024-10-10\t13:55:36\t1.2.3.4\tGET\t/about\t200";
+
+        let result = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config);
+        assert!(result.is_err_and(|error| error.reason() == "The #Fields header was not found"));
+    }
+
+    #[test]
+    fn plus_in_the_query_is_a_space() {
+        let url = Url::parse("https://example.com/search?q=red+shoes&tag=a%2Bb").unwrap();
+        let query = parse_query(&url);
+
+        assert_eq!(query["q"], "red shoes");
+        assert_eq!(query["tag"], "a+b");
+    }
+
+    #[test]
+    fn percent_20_in_the_query_is_a_space() {
+        let request = Url::parse("https://example.com/search?q=red%20shoes").unwrap();
+        let referer = Url::parse("https://other.com/?q=red+shoes").unwrap();
+
+        assert_eq!(parse_query(&request)["q"], "red shoes");
+        assert_eq!(parse_query(&request), parse_query(&referer));
+    }
+
+    #[test]
+    fn tz_offset_is_empty_without_offset() {
+        let line =
+            |time: &str| format!(r#"1.2.3.4 - - [{}] "GET / HTTP/1.1" 200 512 "-" "-""#, time);
+        let config = ParseConfig::new(0, "https://example.com");
+        let mut services = ParserServices::new();
+
+        for (time, expected) in [
+            ("10/Oct/2024:13:55:36 +0200", Some(7200)),
+            ("10/Oct/2024:13:55:36 +0000", Some(0)),
+            ("10/Oct/2024:13:55:36 GMT", Some(0)),
+            ("1728568536", None),
+            ("1728568536123", None),
+        ] {
+            let entry = LogEntry::parse(line(time), &mut services, &config).unwrap();
+            assert_eq!(entry.tz_offset_seconds, expected, "{}", time);
+        }
+    }
+
+    #[test]
+    fn trailing_fields_with_template_variables() {
+        let entry = parse_with_template(
+            r#"$remote_addr [$time_local] "$request" $status $body_bytes_sent "$connection_requests""#,
+            r#"1.2.3.4 [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 "3" edge-1 "a b""#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            entry.extra,
+            Some(serde_json::json!({
+                "connection_requests": "3",
+                "_trailing": ["edge-1", "a b"],
+            }))
+        );
+    }
+
+    #[test]
+    fn user_agent_without_closing_quote() {
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 "-" "Mozilla/5.0 (X11; Linux x86_64"#;
+        let config = ParseConfig::new(0, "https://example.com");
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(
+            entry.user_agent.as_deref(),
+            Some("Mozilla/5.0 (X11; Linux x86_64")
+        );
+        assert!(entry.extra.is_none());
+    }
+
+    #[test]
+    fn redacted_params_in_the_query() {
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /api?token=abc&page=2&API_KEY=x%20y HTTP/1.1" 200 512 "https://example.com/login?password=secret&next=%2F" "-""#;
+        let config = ParseConfig::new(0, "https://example.com").with_redacted_params(vec![
+            "token".to_string(),
+            "api_key".to_string(),
+            "password".to_string(),
+        ]);
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        // The raw query
+        assert_eq!(
+            entry.query.as_deref(),
+            Some("token=REDACTED&page=2&API_KEY=REDACTED")
+        );
+        assert_eq!(
+            entry.referer_query.as_deref(),
+            Some("password=REDACTED&next=%2F")
+        );
+
+        // The parsed query
+        let query: HashMap<String, String> = query_pairs(entry.query.as_deref().unwrap()).collect();
+        assert_eq!(query["token"], "REDACTED");
+        assert_eq!(query["API_KEY"], "REDACTED");
+        assert_eq!(query["page"], "2");
+
+        let referer = parse_query(entry.referer.as_ref().unwrap());
+        assert_eq!(referer["password"], "REDACTED");
+        assert_eq!(referer["next"], "/");
+    }
+
+    fn parse_with_syslog(header: &str) -> LogEntry {
+        let format = LineFormat {
+            syslog: true,
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+        let line = format!(
+            r#"{}1.2.3.4 - - [05/Jan/2025:13:55:36 +0000] "GET /about HTTP/1.1" 200 512 "-" "-""#,
+            header
+        );
+
+        LogEntry::parse(line, &mut ParserServices::new(), &config).unwrap()
+    }
+
+    #[test]
+    fn rfc3164_syslog_header() {
+        for header in [
+            "<34>Jan  5 13:55:36 web-1 nginx: ",
+            "Jan 15 13:55:36 web-1 nginx[1234]: ",
+        ] {
+            let entry = parse_with_syslog(header);
+            assert_eq!(entry.ip.to_string(), "1.2.3.4");
+            assert_eq!(entry.path, "/about");
+        }
+    }
+
+    #[test]
+    fn rfc5424_syslog_header() {
+        for header in [
+            "<34>1 2025-01-05T13:55:36.003Z web-1 nginx 1234 - - ",
+            r#"<165>1 2025-01-05T13:55:36Z web-1 nginx - access [meta seq="1\]"] "#,
+        ] {
+            let entry = parse_with_syslog(header);
+            assert_eq!(entry.ip.to_string(), "1.2.3.4");
+            assert_eq!(entry.path, "/about");
+        }
+    }
+
+    #[test]
+    fn cloned_config_parses_the_same() {
+        let config = ParseConfig::new(0, "https://example.com")
+            .with_redacted_params(vec!["token".to_string()]);
+        let copy = config.clone();
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /about?token=abc HTTP/1.1" 200 512 "-" "-""#;
+
+        let entries: Vec<LogEntry> = std::thread::scope(|scope| {
+            [&config, &copy]
+                .map(|config| {
+                    scope.spawn(move || {
+                        LogEntry::parse(line.to_string(), &mut ParserServices::new(), config)
+                            .unwrap()
+                    })
+                })
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        for entry in entries {
+            assert_eq!(entry.path, "/about");
+            assert_eq!(entry.query.as_deref(), Some("token=REDACTED"));
+        }
+    }
+
+    #[test]
+    fn mixed_case_hosts() {
+        let format = LineFormat {
+            vhost: true,
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://Example.com").with_format(format);
+        let line = r#"WWW.Example.COM:443 1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET https://EXAMPLE.com/Docs/Logo.PNG HTTP/1.1" 200 512 "-" "-""#;
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(entry.vhost.as_deref(), Some("www.example.com"));
+        assert_eq!(entry.vhost_port, Some(443));
+        assert_eq!(entry.path, "/Docs/Logo.PNG");
+        assert_eq!(entry.extension.as_deref(), Some("png"));
+
+        let config = config.with_lowercase_path(true);
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+        assert_eq!(entry.path, "/docs/logo.png");
+    }
+
+    #[test]
+    fn single_quoted_fields() {
+        let format = LineFormat {
+            quote: '\'',
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] 'GET /about HTTP/1.1' 200 512 'https://google.com/' 'Mozilla/5.0 (say "hi")'"#;
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(entry.path, "/about");
+        assert_eq!(
+            entry.referer.map(|referer| referer.to_string()).as_deref(),
+            Some("https://google.com/")
+        );
+        assert_eq!(
+            entry.user_agent.as_deref(),
+            Some(r#"Mozilla/5.0 (say "hi")"#)
+        );
+    }
+
+    #[test]
+    fn malformed_request_lines() {
+        for (request, reason) in [
+            ("GET/ HTTP/1.1", "Malformed request line"),
+            ("\\x16\\x03\\x01", "Malformed request line"),
+            ("GET HTTP/1.1", "Malformed request line (missing path)"),
+            ("GET  HTTP/1.1", "Malformed request line (missing path)"),
+            ("GET /about", "HTTP version not found"),
+            ("", "Empty request"),
+        ] {
+            let result = split_request("", request);
+            assert!(
+                result.is_err_and(|error| error.reason() == reason),
+                "{}",
+                request
+            );
+        }
+
+        assert_eq!(
+            split_request("", "GET /about HTTP/1.1").unwrap(),
+            ("GET", "/about", "HTTP/1.1")
+        );
+    }
+
+    #[test]
+    fn timestamps_with_named_zones() {
+        for (value, expected) in [
+            ("05/Jan/2024:13:55:36 GMT", "2024-01-05T13:55:36+00:00"),
+            ("05/Jan/2024:13:55:36 UTC", "2024-01-05T13:55:36+00:00"),
+            (
+                "05/Jan/2024:13:55:36 Europe/Madrid",
+                "2024-01-05T13:55:36+01:00",
+            ),
+            (
+                "05/Jul/2024:13:55:36 Europe/Madrid",
+                "2024-07-05T13:55:36+02:00",
+            ),
+            (
+                "05/Jan/2024:13:55:36 America/New_York",
+                "2024-01-05T13:55:36-05:00",
+            ),
+        ] {
+            let (timestamp, has_offset) = parse_timestamp(value).unwrap();
+            assert_eq!(timestamp.to_rfc3339(), expected);
+            assert!(has_offset);
+        }
+
+        assert!(parse_timestamp("05/Jan/2024:13:55:36 Mars/Olympus").is_none());
+        assert!(parse_timestamp("05/Jan/2024:13:55:36").is_none());
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert_eq!(unescape(r#"say \x22hi\x22"#), r#"say "hi""#);
+        assert_eq!(unescape(r"https:\/\/google.com\/"), "https://google.com/");
+        assert_eq!(unescape(r"caf\xC3\xA9 é"), "café é");
+        assert_eq!(unescape(r"\q \x2"), r"\q \x2");
+    }
+
+    #[test]
+    fn escaped_fields() {
+        let format = LineFormat {
+            escaped: true,
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /about HTTP/1.1" 200 512 "https:\/\/google.com\/" "Mozilla\/5.0 (say \x22hi\x22)""#;
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(
+            entry.referer.map(|referer| referer.to_string()).as_deref(),
+            Some("https://google.com/")
+        );
+        assert_eq!(
+            entry.user_agent.as_deref(),
+            Some(r#"Mozilla/5.0 (say "hi")"#)
+        );
+    }
+
+    #[test]
+    fn non_utf8_percent_sequences() {
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /files/caf%E9%20menu.pdf?q=%FF HTTP/1.1" 200 512 "-" "-""#;
+        let config = ParseConfig::new(0, "https://example.com");
+        let entry = LogEntry::parse(line.to_string(), &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(entry.request_target, "/files/caf%E9%20menu.pdf?q=%FF");
+        assert_eq!(entry.path, "/files/caf\u{fffd} menu.pdf");
+        assert_eq!(entry.extension.as_deref(), Some("pdf"));
+    }
+
+    #[test]
+    fn epoch_seconds_and_milliseconds() {
+        for (value, expected) in [
+            ("1704462936", "2024-01-05T13:55:36+00:00"),
+            ("1704462936.123", "2024-01-05T13:55:36.123+00:00"),
+            ("1704462936123", "2024-01-05T13:55:36.123+00:00"),
+            ("1704462936123456", "2024-01-05T13:55:36.123456+00:00"),
+            ("1704462936123456789", "2024-01-05T13:55:36.123456789+00:00"),
+        ] {
+            assert_eq!(epoch_timestamp(value).unwrap().to_rfc3339(), expected);
+        }
+
+        for value in ["", "-1704462936", "1.2.3", "17044629e6", "0x1F"] {
+            assert!(epoch_timestamp(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn epoch_inside_the_brackets_of_the_combined_format() {
+        let line =
+            |time: &str| format!(r#"1.2.3.4 - - [{}] "GET / HTTP/1.1" 200 512 "-" "-""#, time);
+        let config = ParseConfig::new(0, "https://example.com");
+        let mut services = ParserServices::new();
+
+        for time in ["1704462936", "1704462936.123", "1704462936123"] {
+            let entry = LogEntry::parse(line(time), &mut services, &config).unwrap();
+            assert_eq!(
+                entry.exact_timestamp.timestamp_millis() / 1000,
+                1704462936,
+                "{}",
+                time
+            );
         }
+
+        let result = LogEntry::parse(line("yesterday"), &mut services, &config);
+        assert!(result.is_err_and(|error| error.reason() == "Invalid datetime"));
+    }
+
+    #[test]
+    fn ipv6_with_zone() {
+        let line = |ip: &str| {
+            format!(
+                r#"{} - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 "-" "-""#,
+                ip
+            )
+        };
+        let config = ParseConfig::new(0, "https://example.com");
+        let mut services = ParserServices::new();
+
+        let entry = LogEntry::parse(line("fe80::1%eth0"), &mut services, &config).unwrap();
+        assert_eq!(entry.ip.to_string(), "fe80::1");
+
+        // The zone is only valid in IPv6 addresses
+        let result = LogEntry::parse(line("1.2.3.4%eth0"), &mut services, &config);
+        assert!(result.is_err_and(|error| error.reason() == "Invalid IP"));
+    }
+
+    #[test]
+    fn canonical_query_of_differently_ordered_queries() {
+        let canonical = |url: &str| canonical_query(&Url::parse(url).unwrap());
+
+        assert_eq!(canonical("https://example.com/?b=2&a=1"), "a=1&b=2");
+        assert_eq!(
+            canonical("https://example.com/?b=2&a=1&c=x%20y"),
+            canonical("https://example.com/?c=x+y&a=1&b=2")
+        );
+
+        let line = |query: &str| {
+            format!(
+                r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /search?{} HTTP/1.1" 200 512 "-" "-""#,
+                query
+            )
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_canonical_query(true);
+        let mut services = ParserServices::new();
+        let first = LogEntry::parse(line("q=shoes&page=2"), &mut services, &config).unwrap();
+        let second = LogEntry::parse(line("page=2&q=shoes"), &mut services, &config).unwrap();
+
+        assert_eq!(first.canonical_query.as_deref(), Some("page=2&q=shoes"));
+        assert_eq!(first.canonical_query, second.canonical_query);
+        assert_ne!(first.query, second.query);
+    }
+
+    #[test]
+    fn same_host_ignores_the_scheme_and_the_port() {
+        for (origin, request, expected) in [
+            ("http://example.com", "https://example.com/", true),
+            ("http://example.com", "https://example.com:443/", true),
+            ("https://example.com", "http://example.com/", true),
+            ("https://example.com", "http://example.com:80/", true),
+            ("https://example.com:443", "http://example.com:8080/", true),
+            ("https://example.com", "https://example.com./", true),
+            ("https://example.com", "https://www.example.com/", false),
+            ("https://example.com", "https://example.org:443/", false),
+        ] {
+            let (origin, request) = (Url::parse(origin).unwrap(), Url::parse(request).unwrap());
+            assert_eq!(
+                same_host(&request, &origin),
+                expected,
+                "{} {}",
+                origin,
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn size_with_separators() {
+        let line = |size: &str| {
+            format!(
+                r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 {} "-" "-""#,
+                size
+            )
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_number_separators(",_");
+        let mut services = ParserServices::new();
+
+        for (size, expected) in [("1,234,567", 1234567), ("1_024", 1024), ("512", 512)] {
+            let entry = LogEntry::parse(line(size), &mut services, &config).unwrap();
+            assert_eq!(entry.size, expected);
+        }
+
+        for size in ["1.234", "12kb", ","] {
+            let result = LogEntry::parse(line(size), &mut services, &config);
+            assert!(result.is_err_and(|error| error.reason() == "Invalid size"));
+        }
+
+        // Without separators, the size is strict
+        let config = ParseConfig::new(0, "https://example.com");
+        let result = LogEntry::parse(line("1,234"), &mut services, &config);
+        assert!(result.is_err_and(|error| error.reason() == "Invalid size"));
+    }
+
+    #[test]
+    fn tab_delimited_fields() {
+        let format = LineFormat {
+            delimiter: '\t',
+            ..LineFormat::default()
+        };
+        let config = ParseConfig::new(0, "https://example.com").with_format(format);
+        let line = [
+            "1.2.3.4",
+            "-",
+            "john doe",
+            "[10/Oct/2024:13:55:36 +0000]",
+            r#""GET /about HTTP/1.1""#,
+            "200",
+            "512",
+            r#""https://google.com/""#,
+            r#""Mozilla/5.0 (X11; Linux x86_64)""#,
+            "edge 1",
+            "0.012",
+        ]
+        .join("\t");
+        let entry = LogEntry::parse(line, &mut ParserServices::new(), &config).unwrap();
+
+        assert_eq!(entry.ip.to_string(), "1.2.3.4");
+        assert_eq!(entry.user.as_deref(), Some("john doe"));
+        assert_eq!(entry.path, "/about");
+        assert_eq!(entry.size, 512);
+        assert_eq!(
+            entry.user_agent.as_deref(),
+            Some("Mozilla/5.0 (X11; Linux x86_64)")
+        );
+        assert_eq!(entry.extra, Some(serde_json::json!(["edge 1", "0.012"])));
     }
 }
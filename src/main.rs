@@ -1,132 +1,491 @@
+mod aggregate;
+mod cache;
+mod db;
+mod export;
+mod infer;
+mod metrics;
+mod options;
+mod pipeline;
+mod split;
+mod summary;
+
+use aggregate::Aggregator;
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
 use duckdb::{params, Connection};
-use log2duck::ParseConfig;
-use log2duck::{LogEntry, LogError, ParserServices};
+use flate2::read::MultiGzDecoder;
+use log2duck::{detect_origin, first_timestamp, parse_lines, LineFormat, ListFilter, ParseConfig};
+use log2duck::{query_pairs, GeoLocation, LogEntry, LogFormat, Profile, RawEntry};
+use metrics::Metrics;
+use options::{Options, Source};
+use pipeline::Parsed;
+use serde_json::{json, Value};
+use split::DailyDatabases;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufWriter};
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use summary::{SizeHistogram, Summary};
+use url::Url;
 
-fn main() -> () {
-    let args: Vec<String> = env::args().collect();
+// Number of lines read to detect the origin and the first timestamp of a log file
+const ORIGIN_SAMPLE_SIZE: usize = 1000;
+
+// Number of lines parsed by --dry-run-schema and --infer
+const SCHEMA_SAMPLE_SIZE: usize = 1000;
+
+// Maximum time the errors are kept in the buffer before writing them to the file
+const ERROR_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time between saves of the rows read from streams (named pipes, stdin or --follow),
+// if --commit-interval is not passed
+const STREAM_COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Time waiting for new lines with --follow
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
+// Number of rows staged with --split-by-day before moving them to the daily databases
+const SPLIT_SAVE_ROWS: usize = 100_000;
+
+// First bytes of the gzip files
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+fn main() -> () {
     // Show help() if there's no arguments
-    if args.len() < 3 {
+    if env::args().len() < 2 {
         return help();
     }
 
-    let input = args.get(1).unwrap();
-    let origin = args.get(2).unwrap();
-    let output = replace_extension(&input, ".db");
-    let errors = replace_extension(&input, ".err");
+    let mut options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            println!("Error: {}", error);
+            return help();
+        }
+    };
+
+    if options.explain {
+        return explain(&options);
+    }
+
+    if let Some(database) = &options.regeo {
+        return regeo(&options, database);
+    }
+
+    if options.infer {
+        return infer::infer(&mut options);
+    }
+
+    // The named pipes (and stdin) are read until the writer stops, so the rows are saved periodically
+    if options.commit_interval.is_none()
+        && (options.follow
+            || options
+                .sources
+                .iter()
+                .any(|source| is_fifo(&source.input) || is_stdin(&source.input)))
+    {
+        options.commit_interval = Some(STREAM_COMMIT_INTERVAL);
+    }
+
+    // Detect the origins passed as "auto"
+    let format = line_format(&options);
+    for source in options.sources.iter_mut() {
+        if source.origin != "auto" {
+            continue;
+        }
+
+        // The lines read to detect the origin would be lost
+        if is_stdin(&source.input) {
+            println!("Error: Unable to detect the origin of stdin, please pass it explicitly");
+            return;
+        }
+        if is_fifo(&source.input) {
+            println!(
+                "Error: Unable to detect the origin of the named pipe {}, please pass it explicitly",
+                source.input
+            );
+            return;
+        }
+
+        let lines = read_log_file(&source.input, options.format).take(ORIGIN_SAMPLE_SIZE);
+        match detect_origin(lines, &format) {
+            Some(origin) => {
+                println!("Detected origin {} for {}", origin, source.input);
+                source.origin = origin;
+            }
+            None => {
+                println!(
+                    "Error: Unable to detect the origin of {}, please pass it explicitly",
+                    source.input
+                );
+                return;
+            }
+        }
+    }
+
+    if options.dry_run_schema {
+        return dry_run_schema(&options);
+    }
 
-    return parse(input, &output, &errors, origin);
+    let (output, errors) = match (&options.output, &options.manifest) {
+        (Some(output), _) => (
+            output.clone(),
+            Path::new(output)
+                .with_extension("err")
+                .to_string_lossy()
+                .to_string(),
+        ),
+        (None, Some(manifest)) => {
+            let name = Path::new(manifest).with_extension("");
+            let name = name.to_string_lossy();
+            (
+                replace_extension(&name, ".db"),
+                replace_extension(&name, ".err"),
+            )
+        }
+        (None, None) => {
+            let name = file_name(&options.sources[0].input);
+            (
+                replace_extension(&name, ".db"),
+                replace_extension(&name, ".err"),
+            )
+        }
+    };
+
+    // Import the files passed together from the oldest, so the newer logs don't skip them
+    if options.manifest.is_none() && options.sources.len() > 1 {
+        sort_by_first_timestamp(&mut options.sources, &format);
+    }
+
+    if options.aggregate_only {
+        return aggregate(&options, &output, &errors);
+    }
+
+    return parse(&options, &output, &errors);
 }
 
-fn parse(input: &str, output: &str, errors: &str, origin: &str) {
+/**
+ * Count the requests by the --group-by dimensions and save only the counts
+ * to the aggregates table, without storing the entries.
+ */
+fn aggregate(options: &Options, output: &str, errors: &str) {
     println!("Preparing to read log file...");
 
-    // Create the duckdb database and the required tables
-    let conn = Connection::open(output).unwrap();
-    conn.execute_batch(r"
-    CREATE TYPE METHOD AS ENUM ('GET', 'POST', 'PUT', 'DELETE', 'HEAD', 'OPTIONS', 'CONNECT', 'TRACE', 'PATCH');
-    CREATE TYPE HTTP_VERSION AS ENUM ('HTTP/1.0', 'HTTP/1.1', 'HTTP/2.0', 'HTTP/3.0');
-    CREATE TABLE IF NOT EXISTS log (
-        ip                   VARCHAR NOT NULL,
-        identity             VARCHAR,
-        user                 VARCHAR,
-        timestamp            TIMESTAMP NOT NULL,
-        method               METHOD NOT NULL,
-        path                 VARCHAR NOT NULL,
-        extension            VARCHAR,
-        query                VARCHAR,
-        http_version         HTTP_VERSION NOT NULL,
-        status_code          USMALLINT NOT NULL,
-        size                 UINTEGER NOT NULL,
-        referer              VARCHAR,
-        referer_origin       VARCHAR,
-        referer_path         VARCHAR,
-        referer_query        VARCHAR,
-        user_agent           VARCHAR,
-        browser              VARCHAR,
-        browser_major        USMALLINT,
-        browser_minor        USMALLINT,
-        browser_patch        USMALLINT,
-        browser_patch_minor  USMALLINT,
-        os                   VARCHAR,
-        os_major             USMALLINT,
-        os_minor             USMALLINT,
-        os_patch             USMALLINT,
-        os_patch_minor       USMALLINT,
-        device               VARCHAR,
-        brand                VARCHAR,
-        model                VARCHAR,
-        country              VARCHAR,
-        continent            VARCHAR,
-        asn                  VARCHAR,
-        as_name              VARCHAR,
-        as_domain            VARCHAR,
-    );
-    ").unwrap();
+    let lock_timeout = Duration::from_secs_f64(options.lock_timeout);
+    let mut conn = match db::open(output, lock_timeout) {
+        Ok(conn) => conn,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+
+    let mut services = pipeline::services(options);
+    match cache::new(options) {
+        Ok(cache) => services.set_shared_cache(cache),
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    }
 
-    // Get the most recent change in the database
-    let last_element: Result<Option<i64>, _> = conn.query_row(
-        "SELECT timestamp FROM log ORDER BY timestamp DESC LIMIT 1",
-        [],
-        |row| row.get(0),
+    let mut error_file = ErrorFile::new(open_or_create_file(errors));
+    let mut aggregator = Aggregator::new(options.group_by.clone());
+    let mut parsed = 0;
+    let mut err_found = 0;
+
+    for source in &options.sources {
+        if options.sources.len() > 1 {
+            println!("Reading {}...", source.input);
+        }
+
+        let config = parse_config(options, &[], source);
+        let lines = read_log_file(&source.input, options.format);
+
+        for (number, result) in parse_lines(lines, &mut services, &config) {
+            match result {
+                Ok(log) => {
+                    parsed += 1;
+                    aggregator.add(&log, source);
+                }
+                Err(error) => {
+                    if !error.is_filtered() {
+                        err_found += 1;
+                        writeln!(error_file, "{}:{} {}", source.input, number, error).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(error) = aggregator.save(&mut conn) {
+        println!("Error: {}", error);
+        return;
+    }
+
+    println!("Process finished!");
+    println!(
+        "{} logs aggregated in {} rows of the database {}",
+        parsed,
+        aggregator.len(),
+        output
     );
-    let timestamp = match last_element {
-        Ok(Some(timestamp)) => timestamp,
-        Ok(None) | Err(_) => 0,
+
+    if let Ok(data) = error_file.metadata() {
+        if data.len() > 0 {
+            println!("{err_found} errors were saved to {}", errors);
+        } else {
+            std::fs::remove_file(errors).unwrap();
+        }
+    }
+}
+
+fn parse(options: &Options, output: &str, errors: &str) {
+    let started = Instant::now();
+    let started_at = Utc::now();
+    println!("Preparing to read log file...");
+
+    // Create the duckdb database and the required tables.
+    // With --split-by-day, the rows are staged in memory and moved to the daily databases
+    let lock_timeout = Duration::from_secs_f64(options.lock_timeout);
+    let mut days = if options.split_by_day {
+        let name = output.trim_end_matches(".db");
+        Some(DailyDatabases::new(
+            name,
+            lock_timeout,
+            options.auto_migrate,
+            options.vhost,
+        ))
+    } else {
+        None
+    };
+    let conn = if days.is_some() {
+        Connection::open_in_memory().map_err(|err| err.to_string())
+    } else {
+        db::open(output, lock_timeout)
+    };
+    let mut conn = match conn {
+        Ok(conn) => conn,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+    if let Err(error) = db::setup(&conn, options.auto_migrate) {
+        println!("Error: {}", error);
+        return;
+    }
+    let cache = match cache::new(options) {
+        Ok(cache) => cache,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+
+    // Check the columns of --top, --count-distinct and --require-columns before importing
+    let columns = db::columns(&conn, "log").unwrap();
+    if let Some((option, column)) = options
+        .top
+        .iter()
+        .map(|(column, _)| ("--top", column))
+        .chain(
+            options
+                .count_distinct
+                .iter()
+                .map(|column| ("--count-distinct", column)),
+        )
+        .chain(
+            options
+                .require_columns
+                .iter()
+                .map(|column| ("--require-columns", column)),
+        )
+        .find(|(_, column)| !columns.contains(column))
+    {
+        println!(
+            "Error: Invalid column {} for {} (valid: {})",
+            column,
+            option,
+            columns.join(", ")
+        );
+        return;
+    }
+
+    // Get the most recent change in the database of every source and vhost
+    // (the daily databases check their own cursors)
+    let cursors = match db::cursors(&conn) {
+        Ok(cursors) => cursors,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
     };
 
-    // Read the log file, skipping old logs
-    let config = ParseConfig::new(timestamp, origin);
-    let lines = read_log_file(input);
+    let mut error_file = ErrorFile::new(open_or_create_file(errors));
+    if options.flatten_query {
+        if let Err(error) = db::create_query_params(&conn) {
+            println!("Error: {}", error);
+            return;
+        }
+    }
+    if options.errors_to_db {
+        if let Err(error) = db::create_parse_errors(&conn) {
+            println!("Error: {}", error);
+            return;
+        }
+    }
 
-    let mut error_file = open_or_create_file(errors);
     let mut app = conn.appender("log").unwrap();
-    let mut services = ParserServices::new();
+    let mut params_app = conn.appender("query_params").ok();
+    let mut errors_app = if options.errors_to_db {
+        Some(conn.appender("parse_errors").unwrap())
+    } else {
+        None
+    };
+    let mut profile = Profile::default();
     let mut new = 0;
     let mut existing = 0;
     let mut err_found = 0;
+    let mut statuses: BTreeMap<u16, usize> = BTreeMap::new();
+    let mut path_counts: HashMap<(String, NaiveDate), usize> = HashMap::new();
+    let mut sizes = SizeHistogram::new(options.size_buckets.clone());
+    let mut imported_range: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+    let mut last_imported = db::LastImported::new();
+    let mut populated: BTreeMap<String, usize> = BTreeMap::new();
+    let format = line_format(options);
+    let mut committed = Instant::now();
     println!("Searching new logs...");
 
     // Append logs to the database
-    for result in parse_line(lines, &mut services, config) {
+    let append = |parsed: Parsed| {
+        let Parsed {
+            source,
+            number,
+            result,
+        } = parsed;
+
         let log = match result {
             Ok(log) => log,
             Err(error) => {
                 if !error.is_filtered() {
                     err_found = err_found + 1;
-                    writeln!(error_file, "{}", error).unwrap();
+                    if options.keep_all {
+                        db::insert_invalid(&conn, source, &error, &format).unwrap();
+                    }
+                    if let Some(errors_app) = errors_app.as_mut() {
+                        errors_app
+                            .append_row(params![
+                                source.label,
+                                source.input,
+                                number,
+                                error.reason(),
+                                error.line()
+                            ])
+                            .unwrap();
+                    }
+                    if !options.keep_all && !options.errors_to_db {
+                        writeln!(error_file, "{}:{} {}", source.input, number, error).unwrap();
+                    }
                 } else {
                     existing = existing + 1;
                     if existing % 50000 == 0 {
-                        println!("Skipped duplicated logs: {}", existing);
+                        let message = format!("Skipped duplicated logs: {}", existing);
+                        print_progress(options, &message, new, existing);
                     }
                 }
-                continue;
+                return;
+            }
+        };
+
+        if let Some(days) = days.as_mut() {
+            if !days.accepts(source, &log).unwrap() {
+                existing = existing + 1;
+                if existing % 50000 == 0 {
+                    let message = format!("Skipped duplicated logs: {}", existing);
+                    print_progress(options, &message, new, existing);
+                }
+                return;
             }
+        }
+
+        if !options.require_columns.is_empty() {
+            count_populated(&log, &mut populated);
+        }
+
+        let request_id = if options.flatten_query {
+            Some(request_id(&log.line))
+        } else {
+            None
         };
 
+        let (date, hour, weekday) = if options.time_columns {
+            (
+                Some(log.timestamp.date_naive().to_string()),
+                Some(log.timestamp.hour() as u16),
+                Some(log.timestamp.weekday().number_from_monday() as u16),
+            )
+        } else {
+            (None, None, None)
+        };
+
+        let time = Instant::now();
         let result = app.append_row(params![
+            source.label,
+            source.input,
+            request_id,
+            log.trace_id,
+            log.vhost,
+            log.vhost_port,
+            log.server_port,
             log.ip.to_string(),
+            log.x_forwarded_for,
+            log.visitor_id,
+            log.session_id,
             log.identity,
             log.user,
             log.timestamp.to_string(),
+            log.tz_offset_seconds,
+            date,
+            hour,
+            weekday,
             log.method.to_string(),
+            log.scheme,
+            log.request_target,
             log.path,
+            log.route,
+            log.section,
             log.extension,
+            log.is_asset,
             log.query,
+            log.canonical_query,
+            log.fragment,
             log.http_version.to_string(),
             log.status_code,
             log.size,
+            log.request_length,
+            log.bytes_sent,
+            log.request_time,
+            log.upstream_time,
+            log.upstream_addr,
+            log.cache_status,
+            log.content_type,
+            log.connection_status,
+            log.ssl_protocol,
+            log.ssl_cipher,
             log.referer.map(|url| url.to_string()),
             log.referer_origin
                 .map(|origin| origin.unicode_serialization()),
             log.referer_path,
+            log.referer_has_path,
             log.referer_query,
             log.user_agent,
             log.browser,
@@ -142,27 +501,220 @@ fn parse(input: &str, output: &str, errors: &str, origin: &str) {
             log.device,
             log.brand,
             log.model,
+            log.ua_canonical,
             log.country,
+            log.country_code,
             log.continent,
+            log.continent_code,
             log.asn,
             log.as_name,
             log.as_domain,
+            log.geo_raw.map(|raw| raw.to_string()),
+            log.ptr_hostname,
+            log.extra.map(|extra| extra.to_string()),
+            None::<String>,
+            None::<String>,
         ]);
+        profile.appending += time.elapsed();
 
         if let Err(err) = result {
             err_found = err_found + 1;
-            writeln!(error_file, "Database error: {} ({})", log.line, err).unwrap();
-            continue;
+            if let Some(errors_app) = errors_app.as_mut() {
+                errors_app
+                    .append_row(params![
+                        source.label,
+                        source.input,
+                        number,
+                        format!("Database error: {}", err),
+                        log.line
+                    ])
+                    .unwrap();
+                return;
+            }
+            writeln!(
+                error_file,
+                "{}:{} Database error: {} ({})",
+                source.input, number, log.line, err
+            )
+            .unwrap();
+            return;
+        }
+
+        // Save every query parameter in a row
+        if let (Some(params_app), Some(request_id), Some(query)) =
+            (params_app.as_mut(), &request_id, &log.query)
+        {
+            for (key, value) in query_pairs(query) {
+                params_app
+                    .append_row(params![request_id, key, value])
+                    .unwrap();
+            }
         }
 
         new = new + 1;
+        let cursor = last_imported
+            .entry((source.label.clone(), log.vhost.clone()))
+            .or_insert(0);
+        *cursor = (*cursor).max(log.exact_timestamp.timestamp_micros());
+        *statuses.entry(log.status_code).or_insert(0) += 1;
+        sizes.add(log.size);
+        imported_range = match imported_range {
+            Some((from, to)) => Some((from.min(log.timestamp), to.max(log.timestamp))),
+            None => Some((log.timestamp, log.timestamp)),
+        };
+        if options.incremental_rollups {
+            let key = (log.path, log.timestamp.date_naive());
+            *path_counts.entry(key).or_insert(0) += 1;
+        }
         if new % 50000 == 0 {
-            println!("Adding new logs: {}", new);
+            let message = format!("Adding new logs: {}", new);
+            print_progress(options, &message, new, existing);
+        }
+
+        // Save the rows appended so far, so they are kept if the import fails later
+        if options.commit_every.is_some_and(|rows| new % rows == 0)
+            || options
+                .commit_interval
+                .is_some_and(|interval| committed.elapsed() >= interval)
+            || (days.is_some() && new % SPLIT_SAVE_ROWS == 0)
+        {
+            let time = Instant::now();
+            app.flush().unwrap();
+            if let Some(days) = days.as_mut() {
+                days.save(&conn).unwrap();
+            }
+            profile.appending += time.elapsed();
+            committed = Instant::now();
+        }
+    };
+
+    let parsing = panic::catch_unwind(AssertUnwindSafe(|| {
+        if options.threads > 1 {
+            pipeline::threaded(options, &cursors, options.threads, cache, append)
+        } else if options.jobs > 1 {
+            pipeline::parallel(options, &cursors, options.jobs, cache, append)
+        } else {
+            pipeline::serial(options, &cursors, cache, append)
+        }
+    }));
+
+    // Save the rows appended before an unexpected error, so the next import continues after them
+    let parsing = match parsing {
+        Ok(parsing) => parsing,
+        Err(panic) => {
+            let saved = app.flush().is_ok()
+                && params_app.as_mut().is_none_or(|app| app.flush().is_ok())
+                && errors_app.as_mut().is_none_or(|app| app.flush().is_ok())
+                && days.as_mut().is_none_or(|days| days.save(&conn).is_ok());
+            let last = imported_range.map_or(String::from("none"), |(_, to)| to.to_string());
+
+            if saved {
+                println!(
+                    "Error: The import was interrupted after adding {} logs (the most recent from {}). They were saved, the next import continues after them",
+                    new, last
+                );
+            } else {
+                println!(
+                    "Error: The import was interrupted after adding {} logs (the most recent from {}), but they couldn't be saved",
+                    new, last
+                );
+            }
+            panic::resume_unwind(panic);
+        }
+    };
+    profile.reading = parsing.reading;
+    profile.parsing = parsing.parsing;
+    profile.user_agent = parsing.user_agent;
+    profile.geolocation = parsing.geolocation;
+
+    // Flush the pending rows
+    drop(app);
+    drop(params_app);
+    drop(errors_app);
+    if let Some(days) = days.as_mut() {
+        if let Err(error) = days.save(&conn) {
+            println!("Error: {}", error);
+            return;
         }
     }
 
-    println!("Process finished!");
-    println!("{} logs added to the database {}", new, output);
+    let origins: Vec<&str> = options
+        .sources
+        .iter()
+        .map(|source| source.origin.as_str())
+        .collect();
+    let import = db::Import {
+        origin: &origins.join(","),
+        format: match options.format {
+            LogFormat::Cloudflare => "cloudflare",
+            LogFormat::Json => "json",
+            LogFormat::S3 => "s3",
+            LogFormat::CloudFront => "cloudfront",
+            LogFormat::Combined if options.vhost => "vhost_combined",
+            LogFormat::Combined => "combined",
+        },
+        started_at,
+        inserted: new,
+    };
+
+    if options.incremental_rollups {
+        db::update_path_counts(&mut conn, &path_counts).unwrap();
+    }
+
+    match days {
+        Some(days) => {
+            let count = days.days();
+            if let Err(error) = days.finish(&conn, &import) {
+                println!("Error: {}", error);
+                return;
+            }
+            println!("Process finished!");
+            println!(
+                "{} logs added to {} daily databases {}-YYYY-MM-DD.db",
+                new,
+                count,
+                output.trim_end_matches(".db")
+            );
+        }
+        None => {
+            db::save_import(&conn, &import).unwrap();
+            db::save_cursors(&conn, &last_imported).unwrap();
+            println!("Process finished!");
+            println!("{} logs added to the database {}", new, output);
+        }
+    }
+
+    if let Some(filename) = &options.parquet {
+        export::parquet(
+            &conn,
+            filename,
+            &options.parquet_codec,
+            options.parquet_row_group_size,
+        )
+        .unwrap();
+        println!("Database exported to {}", filename);
+    }
+
+    if let Some(filename) = &options.csv {
+        export::csv(&conn, filename, &options.csv_null).unwrap();
+        println!("Database exported to {}", filename);
+    }
+
+    if let Some(filename) = &options.arrow {
+        if let Err(error) = export::arrow(&conn, filename) {
+            println!("Error: {}", error);
+            return;
+        }
+        println!("Database exported to {}", filename);
+    }
+
+    if options.keep_all && err_found > 0 {
+        println!("{err_found} invalid lines were saved to the database");
+    }
+
+    if options.errors_to_db && err_found > 0 {
+        println!("{err_found} errors were saved to the parse_errors table");
+    }
 
     if let Ok(data) = error_file.metadata() {
         if data.len() > 0 {
@@ -171,22 +723,767 @@ fn parse(input: &str, output: &str, errors: &str, origin: &str) {
             std::fs::remove_file(errors).unwrap();
         }
     }
+
+    if let Some(filename) = &options.metrics_file {
+        let metrics = Metrics {
+            origin: &origins.join(","),
+            inserted: new,
+            skipped: existing,
+            errors: err_found,
+            statuses: &statuses,
+            duration: started.elapsed(),
+        };
+        metrics.write(filename).unwrap();
+    }
+
+    let mut tops = Vec::new();
+    for (column, limit) in &options.top {
+        let rows = db::top(&conn, column, *limit).unwrap();
+        summary::print_top(column, &rows);
+        tops.push((column.clone(), rows));
+    }
+
+    let mut distinct = Vec::new();
+    for column in &options.count_distinct {
+        distinct.push((column.clone(), db::count_distinct(&conn, column).unwrap()));
+    }
+    if !distinct.is_empty() {
+        summary::print_distinct(&distinct);
+    }
+
+    if options.summary || options.summary_json.is_some() {
+        let hourly = match imported_range {
+            Some((from, to)) => db::hourly_requests(&conn, &from, &to).unwrap(),
+            None => Vec::new(),
+        };
+        let summary = Summary {
+            origin: &origins.join(","),
+            inserted: new,
+            skipped: existing,
+            errors: err_found,
+            statuses: &statuses,
+            sizes: &sizes,
+            tops: &tops,
+            distinct: &distinct,
+            hourly: &hourly,
+            duration: started.elapsed(),
+        };
+
+        if options.summary {
+            summary.print();
+        }
+
+        if let Some(filename) = &options.summary_json {
+            summary.write_json(filename).unwrap();
+            println!("Summary saved to {}", filename);
+        }
+    }
+
+    if options.profile {
+        print_profile(&profile, started.elapsed());
+    }
+
+    if options.json_events {
+        eprintln!(
+            "{}",
+            json!({
+                "event": "done",
+                "inserted": new,
+                "skipped": existing,
+                "errors": err_found,
+                "duration_seconds": started.elapsed().as_secs_f64(),
+            })
+        );
+    }
+
+    // Fail if the format is probably wrong, after saving the rows to check them
+    let missing = match new {
+        0 => Vec::new(),
+        entries => check_required_columns(options, &populated, entries),
+    };
+    if !missing.is_empty() {
+        println!(
+            "Error: Required columns with more than {:.0}% of nulls:",
+            options.max_null_ratio * 100.0
+        );
+        for (column, nulls) in missing {
+            println!("  {:<20} {:>6.1}%", column, nulls * 100.0);
+        }
+        process::exit(1);
+    }
 }
 
-fn parse_line<'a>(
-    iterator: impl Iterator<Item = String> + 'a,
-    mut services: &'a mut ParserServices,
-    config: ParseConfig,
-) -> Box<dyn Iterator<Item = Result<LogEntry, LogError>> + 'a> {
-    Box::new(iterator.map(move |line| LogEntry::parse(line, &mut services, &config)))
+/**
+ * Geolocate again the IPs of an existing database (for example, with a newer IP database)
+ * and update the geolocation columns, without reading the logs again.
+ */
+fn regeo(options: &Options, output: &str) {
+    let lock_timeout = Duration::from_secs_f64(options.lock_timeout);
+    let mut conn = match db::open(output, lock_timeout) {
+        Ok(conn) => conn,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+    if let Err(error) = db::setup(&conn, false) {
+        println!("Error: {}", error);
+        return;
+    }
+
+    let ips = match db::ips(&conn) {
+        Ok(ips) => ips,
+        Err(error) => {
+            println!("Error: {}", error);
+            return;
+        }
+    };
+    println!("Geolocating {} IPs...", ips.len());
+
+    let mut services = pipeline::services(options);
+    let geolocations: Vec<(String, GeoLocation)> = ips
+        .into_iter()
+        .filter_map(|ip| {
+            let geolocation = GeoLocation::clone(&services.get_geolocation(&ip.parse().ok()?));
+            Some((ip, geolocation))
+        })
+        .collect();
+
+    match db::update_geolocations(&mut conn, &geolocations) {
+        Ok(changed) => {
+            println!("Process finished!");
+            println!("{} rows updated in the database {}", changed, output);
+        }
+        Err(error) => println!("Error: {}", error),
+    }
 }
 
-fn read_log_file(filename: &str) -> impl Iterator<Item = String> {
-    let path = Path::new(filename);
-    let file = File::open(path).unwrap();
-    let reader = io::BufReader::new(file);
+/**
+ * Parse a single line, printing to stderr every field split from the line
+ * (with its position) and the parsed entry or the error found.
+ */
+fn explain(options: &Options) {
+    let source = &options.sources[0];
+    let line = &source.input;
+    let format = line_format(options);
+
+    eprintln!("Line: {}", line);
+    eprintln!();
+
+    let mut services = pipeline::services(options);
+    let config = parse_config(options, &[], source);
 
-    reader.lines().filter_map(|line| line.ok())
+    // The JSON and CloudFront lines are not split, their fields are shown in the entry
+    if !matches!(format.base, LogFormat::Combined | LogFormat::S3) {
+        match LogEntry::parse(line.to_string(), &mut services, &config) {
+            Ok(log) => eprintln!("{}", serde_json::to_string_pretty(&log.to_json()).unwrap()),
+            Err(error) if error.is_filtered() => eprintln!("The line is filtered by the options"),
+            Err(error) => eprintln!("Error parsing the line: {}", error.reason()),
+        }
+        return;
+    }
+
+    let raw = match RawEntry::parse(line, &format) {
+        Ok(raw) => raw,
+        Err(error) => {
+            eprintln!("Error splitting the line: {}", error.reason());
+            return;
+        }
+    };
+
+    eprintln!("  {:<14} {:>11}  Value", "Field", "Position");
+    for (name, value) in raw.fields() {
+        // The fields missing in the template have a default value, outside the line
+        let position = match (value.as_ptr() as usize).checked_sub(line.as_ptr() as usize) {
+            Some(start) if start <= line.len() => format!("{}..{}", start, start + value.len()),
+            _ => String::from("-"),
+        };
+        eprintln!("  {:<14} {:>11}  {:?}", name, position, value);
+    }
+    eprintln!();
+
+    match LogEntry::from_raw(raw, &mut services, &config) {
+        Ok(log) => eprintln!("{}", serde_json::to_string_pretty(&log.to_json()).unwrap()),
+        Err(error) if error.is_filtered() => eprintln!("The line is filtered by the options"),
+        Err(error) => eprintln!("Error parsing the line: {}", error.reason()),
+    }
+}
+
+/**
+ * Parse the first lines of every log file, without saving them,
+ * and print how many entries have a value (not null) in every field.
+ */
+fn dry_run_schema(options: &Options) {
+    let mut services = pipeline::services(options);
+    let mut populated: BTreeMap<String, usize> = BTreeMap::new();
+    let mut parsed = 0;
+    let mut errors = 0;
+
+    for source in &options.sources {
+        let config = parse_config(options, &[], source);
+        let lines = read_log_file(&source.input, options.format).take(SCHEMA_SAMPLE_SIZE);
+
+        for (number, result) in parse_lines(lines, &mut services, &config) {
+            let log = match result {
+                Ok(log) => log,
+                Err(error) => {
+                    if !error.is_filtered() {
+                        errors += 1;
+                        println!("{}:{} {}", source.input, number, error);
+                    }
+                    continue;
+                }
+            };
+
+            parsed += 1;
+            count_populated(&log, &mut populated);
+        }
+    }
+
+    println!();
+    println!("{} lines parsed, {} errors", parsed, errors);
+    println!();
+    println!("  {:<20} {:>10}", "Field", "Populated");
+    for (field, count) in &populated {
+        println!("  {:<20} {:>10}", field, count);
+    }
+}
+
+/** Print the progress of the import, or a JSON event to stderr with --json-events */
+fn print_progress(options: &Options, message: &str, inserted: usize, skipped: usize) {
+    if options.json_events {
+        eprintln!(
+            "{}",
+            json!({ "event": "progress", "inserted": inserted, "skipped": skipped })
+        );
+    } else {
+        println!("{}", message);
+    }
+}
+
+/** Count the fields of the entry with a value (not null) */
+fn count_populated(log: &LogEntry, populated: &mut BTreeMap<String, usize>) {
+    if let Value::Object(fields) = log.to_json() {
+        for (field, value) in fields {
+            let count = populated.entry(field).or_insert(0);
+            if !value.is_null() {
+                *count += 1;
+            }
+        }
+    }
+}
+
+/**
+ * Check that the required columns are not null in more than the maximum ratio of the entries.
+ * Returns the columns that fail, with their ratio of nulls.
+ */
+fn check_required_columns(
+    options: &Options,
+    populated: &BTreeMap<String, usize>,
+    entries: usize,
+) -> Vec<(String, f64)> {
+    options
+        .require_columns
+        .iter()
+        .filter_map(|column| {
+            let count = populated.get(column).copied().unwrap_or(0);
+            let nulls = 1.0 - count as f64 / entries as f64;
+            (nulls > options.max_null_ratio).then(|| (column.clone(), nulls))
+        })
+        .collect()
+}
+
+/** Stable id of a request, to join the query_params and log tables: the hash of the line */
+fn request_id(line: &str) -> String {
+    blake3::hash(line.as_bytes()).to_hex()[..16].to_string()
+}
+
+/** Print the time spent in every stage of the import */
+fn print_profile(profile: &Profile, total: Duration) {
+    // User agent and geolocation lookups happen while parsing
+    let parsing = profile
+        .parsing
+        .saturating_sub(profile.user_agent + profile.geolocation);
+
+    println!("");
+    println!("Profile:");
+    println!("  Reading:     {:>10.3}s", profile.reading.as_secs_f64());
+    println!("  Parsing:     {:>10.3}s", parsing.as_secs_f64());
+    println!("  User agents: {:>10.3}s", profile.user_agent.as_secs_f64());
+    println!(
+        "  Geolocation: {:>10.3}s",
+        profile.geolocation.as_secs_f64()
+    );
+    println!("  Appending:   {:>10.3}s", profile.appending.as_secs_f64());
+    println!("  Total:       {:>10.3}s", total.as_secs_f64());
+}
+
+/** Get the variation of the log format from the options */
+fn line_format(options: &Options) -> LineFormat {
+    LineFormat {
+        base: options.format,
+        vhost: options.vhost,
+        quote: options.quote,
+        delimiter: options.delimiter,
+        syslog: options.syslog_strip,
+        escaped: options.unescape,
+        template: options.log_format.clone(),
+        json_keys: Arc::new(options.json_keys.clone().unwrap_or_default()),
+        // Read from the #Fields header of every file
+        cloudfront_fields: None,
+    }
+}
+
+/**
+ * Create the configuration to parse the entries of a source.
+ * The cursor is partitioned by the source label, and by the virtual host with --vhost,
+ * so the logs of a server or host are not skipped because other one is more recent.
+ */
+fn parse_config(options: &Options, cursors: &[db::Cursor], source: &Source) -> ParseConfig {
+    let cursors = cursors
+        .iter()
+        .filter(|cursor| cursor.source == source.label);
+    let config = if options.vhost {
+        let timestamps = cursors
+            .filter_map(|cursor| Some((cursor.vhost.clone()?, cursor.timestamp)))
+            .collect();
+        ParseConfig::new(0, &source.origin).with_vhost_timestamps(timestamps)
+    } else {
+        let timestamp = cursors.map(|cursor| cursor.timestamp).max().unwrap_or(0);
+        ParseConfig::new(timestamp, &source.origin)
+    };
+
+    let config = match options.max_query_length {
+        Some(length) => config.with_max_query_length(length),
+        None => config,
+    };
+
+    let config = match &options.asset_extensions {
+        Some(extensions) => config.with_asset_extensions(extensions.clone()),
+        None => config,
+    };
+    let config = config.with_dedup_consecutive(options.dedup_consecutive);
+    let config = config.with_canonical_query(options.canonical_query);
+    let config = config.with_normalized_ua(options.normalize_ua);
+    let config = match &options.routes {
+        Some(routes) => config.with_routes(routes.clone()),
+        None => config,
+    };
+    let config = config.with_status_range(options.min_status, options.max_status);
+    let config = match &options.number_separators {
+        Some(separators) => config.with_number_separators(separators),
+        None => config,
+    };
+    let config = match &options.path_prefix {
+        Some(prefix) => config.with_path_prefix(prefix),
+        None => config,
+    };
+    let config = match options.time_bucket {
+        Some(bucket) => config.with_time_bucket(bucket),
+        None => config,
+    };
+    let config = match &options.visitor_salt {
+        Some(salt) => config.with_visitor_id(salt),
+        None => config,
+    };
+    let config = match &options.session_cookie {
+        Some(name) => config.with_session_cookie(name),
+        None => config,
+    };
+
+    config
+        .with_format(line_format(options))
+        .with_origins(options.origins.clone())
+        .with_redacted_params(options.redact_params.clone())
+        .with_line_regex(options.match_line.clone(), options.exclude_line.clone())
+        .with_lowercase_path(options.lowercase_path)
+        .with_countries(ListFilter::new(
+            options.only_countries.clone(),
+            options.exclude_countries.clone(),
+        ))
+        .with_continents(ListFilter::new(
+            options.only_continents.clone(),
+            options.exclude_continents.clone(),
+        ))
+}
+
+/**
+ * Read the lines of a log file. With the JSON formats, a file with an array of objects
+ * (starting with `[`) is also accepted, and every object is returned as a line.
+ */
+fn read_log_file(filename: &str, format: LogFormat) -> impl Iterator<Item = String> {
+    let lines: Box<dyn Iterator<Item = String>> = if is_fifo(filename) {
+        Box::new(read_fifo(filename.to_string()))
+    } else {
+        let reader = if is_stdin(filename) {
+            decompress(io::stdin())
+        } else if is_url(filename) {
+            match download(filename) {
+                Ok(response) => decompress(response),
+                // Read nothing, like the files that can't be followed
+                Err(error) => {
+                    println!("Error: {}", error);
+                    decompress(io::empty())
+                }
+            }
+        } else {
+            let path = Path::new(filename);
+            decompress(File::open(path).unwrap())
+        };
+
+        match format {
+            LogFormat::Json | LogFormat::Cloudflare => read_json(reader, filename),
+            _ => Box::new(read_lines(reader, filename)),
+        }
+    };
+
+    // Some Windows tools start the files with a byte order mark
+    lines
+        .enumerate()
+        .map(|(index, line)| match line.strip_prefix('\u{feff}') {
+            Some(rest) if index == 0 => rest.to_string(),
+            _ => line,
+        })
+}
+
+/**
+ * Read the lines of a reader, stopping at the first error (like a broken connection)
+ * instead of retrying it forever. The error is reported, so the truncation is not silent.
+ */
+fn read_lines(reader: impl BufRead, filename: &str) -> impl Iterator<Item = String> {
+    let filename = filename.to_string();
+
+    reader.lines().map_while(move |line| match line {
+        Ok(line) => Some(line),
+        Err(e) => {
+            println!("Error: Unable to read {} ({})", filename, e);
+            None
+        }
+    })
+}
+
+/**
+ * Read the objects of a JSON log, which can be an object per line (JSON lines)
+ * or an array of objects. The array is streamed, without loading the whole file in memory.
+ */
+fn read_json(mut reader: Box<dyn BufRead>, filename: &str) -> Box<dyn Iterator<Item = String>> {
+    // Some Windows tools start the files with a byte order mark
+    let bom = "\u{feff}".as_bytes();
+    if reader
+        .fill_buf()
+        .is_ok_and(|buffer| buffer.starts_with(bom))
+    {
+        reader.consume(bom.len());
+    }
+
+    if peek_json(&mut reader) != Some(b'[') {
+        return Box::new(read_lines(reader, filename));
+    }
+
+    Box::new(JsonArray {
+        reader,
+        filename: filename.to_string(),
+        started: false,
+    })
+}
+
+/** Skip the whitespace and return the next byte, without consuming it */
+fn peek_json(reader: &mut Box<dyn BufRead>) -> Option<u8> {
+    loop {
+        let buffer = reader.fill_buf().ok()?;
+        let skip = buffer
+            .iter()
+            .take_while(|byte| byte.is_ascii_whitespace())
+            .count();
+
+        if skip < buffer.len() {
+            let byte = buffer[skip];
+            reader.consume(skip);
+            return Some(byte);
+        }
+        if buffer.is_empty() {
+            return None;
+        }
+        reader.consume(skip);
+    }
+}
+
+/** Iterator of the elements of a JSON array, returned as compact JSON lines */
+struct JsonArray {
+    reader: Box<dyn BufRead>,
+    filename: String,
+    started: bool,
+}
+
+impl Iterator for JsonArray {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let expected = if self.started { b',' } else { b'[' };
+
+        match peek_json(&mut self.reader) {
+            Some(byte) if byte == expected => self.reader.consume(1),
+            Some(b']') | None => return None,
+            Some(_) => {
+                println!("Error: Invalid JSON array in {}", self.filename);
+                return None;
+            }
+        }
+        self.started = true;
+
+        if peek_json(&mut self.reader) == Some(b']') {
+            return None;
+        }
+
+        let mut values =
+            serde_json::Deserializer::from_reader(&mut self.reader).into_iter::<Value>();
+
+        match values.next()? {
+            Ok(value) => Some(value.to_string()),
+            Err(e) => {
+                println!("Error: Invalid JSON array in {} ({})", self.filename, e);
+                None
+            }
+        }
+    }
+}
+
+/**
+ * Buffer a log file, decompressing it if it's gzipped (like the rotated access.log.2.gz).
+ * The compression is detected by the magic bytes, so the extension doesn't matter.
+ */
+fn decompress(reader: impl Read + 'static) -> Box<dyn BufRead> {
+    let mut reader = io::BufReader::new(reader);
+    let gzipped = reader
+        .fill_buf()
+        .is_ok_and(|buffer| buffer.starts_with(&GZIP_MAGIC_BYTES));
+
+    if gzipped {
+        // logrotate and cat can produce files with several gzip members
+        Box::new(io::BufReader::new(MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    }
+}
+
+/**
+ * Sort the log files by the timestamp of their first entry.
+ * The URLs, named pipes, stdin and the files without valid entries are kept at the end
+ * (the URLs are not downloaded twice to read their first lines).
+ */
+fn sort_by_first_timestamp(sources: &mut [Source], format: &LineFormat) {
+    let timestamps: HashMap<String, DateTime<Utc>> = sources
+        .iter()
+        .filter(|source| {
+            !is_url(&source.input) && !is_fifo(&source.input) && !is_stdin(&source.input)
+        })
+        .filter_map(|source| {
+            let lines = read_log_file(&source.input, format.base).take(ORIGIN_SAMPLE_SIZE);
+            Some((source.input.clone(), first_timestamp(lines, format)?))
+        })
+        .collect();
+
+    sources.sort_by_key(|source| {
+        let timestamp = timestamps.get(&source.input);
+        (timestamp.is_none(), timestamp.copied())
+    });
+}
+
+/** The input "-" is the standard input */
+fn is_stdin(filename: &str) -> bool {
+    filename == "-"
+}
+
+fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+#[cfg(unix)]
+fn is_fifo(filename: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(filename).is_ok_and(|data| data.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_filename: &str) -> bool {
+    false
+}
+
+/**
+ * Read the lines written to a named pipe, without end: opening the pipe waits for a writer,
+ * and when the writer closes it, the pipe is opened again to wait for the next one.
+ */
+fn read_fifo(filename: String) -> impl Iterator<Item = String> {
+    let path = filename.clone();
+
+    iter::repeat_with(move || File::open(&path))
+        .map_while(|file| file.ok())
+        .flat_map(move |file| read_lines(io::BufReader::new(file), &filename))
+}
+
+/**
+ * Read the lines of a log file and wait for the new ones, like `tail -F` (used by --follow).
+ * When the file is rotated (replaced by other file or truncated), the new file is read from the start.
+ */
+struct FollowedFile {
+    filename: String,
+    reader: io::BufReader<File>,
+    id: Option<(u64, u64)>,
+    position: u64,
+    line: Vec<u8>,
+}
+
+impl FollowedFile {
+    fn open(filename: &str) -> io::Result<FollowedFile> {
+        let file = File::open(filename)?;
+        let id = file_id(&file.metadata()?);
+
+        Ok(FollowedFile {
+            filename: filename.to_string(),
+            reader: io::BufReader::new(file),
+            id,
+            position: 0,
+            line: Vec::new(),
+        })
+    }
+
+    /** Whether the path of the file has other file or the file was truncated */
+    fn rotated(&self) -> bool {
+        match std::fs::metadata(&self.filename) {
+            Ok(metadata) => file_id(&metadata) != self.id || metadata.len() < self.position,
+            // The file was moved and the new one is not created yet
+            Err(_) => false,
+        }
+    }
+}
+
+impl Iterator for FollowedFile {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            // The lines are read as bytes, so the invalid UTF-8 is not lost and goes to the parser
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) | Err(_) => {
+                    if self.rotated() {
+                        if let Ok(file) = FollowedFile::open(&self.filename) {
+                            // The last line of the old file may be incomplete, but it's not lost
+                            let partial = std::mem::replace(self, file).line;
+                            if !partial.is_empty() {
+                                return Some(String::from_utf8_lossy(&partial).into_owned());
+                            }
+                            continue;
+                        }
+                    }
+                    thread::sleep(FOLLOW_POLL_INTERVAL);
+                }
+                Ok(read) => {
+                    self.position += read as u64;
+
+                    // Wait for the rest of the lines that are being written
+                    if !self.line.ends_with(b"\n") {
+                        continue;
+                    }
+
+                    let line = std::mem::take(&mut self.line);
+                    let line = String::from_utf8_lossy(&line);
+                    return Some(line.trim_end_matches(['\n', '\r']).to_string());
+                }
+            }
+        }
+    }
+}
+
+/** Identify a file by the device and inode, to detect when it's replaced */
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/** Stream a remote log file, authenticated with the LOG2DUCK_TOKEN env variable if it's defined */
+fn download(url: &str) -> Result<reqwest::blocking::Response, String> {
+    let error = |err: reqwest::Error| format!("Unable to download {} ({})", url, err);
+
+    // Disable the default timeout, the body can take long to be read
+    let client = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .map_err(error)?;
+    let mut request = client.get(url);
+
+    if let Ok(token) = env::var("LOG2DUCK_TOKEN") {
+        request = request.bearer_auth(token);
+    }
+
+    request
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(error)
+}
+
+/** Get the name of the file, used to generate the output files */
+fn file_name(input: &str) -> String {
+    if !is_url(input) {
+        return input.to_string();
+    }
+
+    // Use the last segment of the URL path: https://example.com/logs/access.log -> access.log
+    Url::parse(input)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back().map(|name| name.to_string()))
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("log"))
+}
+
+/**
+ * Buffered errors file, so logs with many rejected lines don't need a write per line.
+ * It's flushed every second (to keep the errors if the import is interrupted)
+ * and when it's dropped, including the early returns and panics.
+ */
+struct ErrorFile {
+    writer: BufWriter<File>,
+    flushed: Instant,
+}
+
+impl ErrorFile {
+    fn new(file: File) -> ErrorFile {
+        ErrorFile {
+            writer: BufWriter::new(file),
+            flushed: Instant::now(),
+        }
+    }
+
+    /** Flush the pending errors and get the metadata of the file */
+    fn metadata(&mut self) -> io::Result<std::fs::Metadata> {
+        self.flush()?;
+        self.writer.get_ref().metadata()
+    }
+}
+
+impl Write for ErrorFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if self.flushed.elapsed() >= ERROR_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushed = Instant::now();
+        self.writer.flush()
+    }
 }
 
 fn open_or_create_file(filename: &str) -> File {
@@ -205,14 +1502,137 @@ fn help() {
     let version = env!("CARGO_PKG_VERSION");
     println!("log2duck {}", version);
     println!("");
-    println!("Run: log2duck <file> <origin>");
+    println!("Run: log2duck [options] <file>... <origin>");
+    println!("     log2duck [options] --manifest <file>");
+    println!("     log2duck [options] --explain <line> <origin>");
+    println!("     log2duck --regeo <database> [--mmdb <file>]");
     println!("Example: log2duck access.log 'https://mydomain.com'");
+    println!("         log2duck access.log auto");
+    println!("         log2duck 'access.log*' 'https://mydomain.com'");
+    println!("         zcat access.log.*.gz | log2duck - 'https://mydomain.com' --output site.db");
+    println!("");
+    println!("Options:");
+    println!("  --manifest <file>            Import several log files with their origins");
+    println!("  --output <file>              The database file (required to read from stdin)");
+    println!("  --origin-file <file>         Accept the requests to the origins of this file too");
+    println!(
+        "  --vhost                      The lines start with the virtual host (vhost_combined)"
+    );
+    println!(
+        "  --format <name>              Format of the lines: combined, cloudflare, json, s3 or cloudfront"
+    );
+    println!(
+        "  --json-keys <list>           Keys of the fields of the JSON lines: ip=client_ip,..."
+    );
+    println!("  --log-format <template>      Format of the lines, with nginx or Apache variables");
+    println!("  --syslog-strip               The lines start with a syslog header");
+    println!("  --quote <single|double>      Quotes of the request, referer and user agent");
+    println!("  --delimiter <space|tab>      Character separating the fields (default: space)");
+    println!(
+        "  --unescape                   Decode the escaped quotes and characters of the fields"
+    );
+    println!("  --number-separators <chars>  Remove these characters from the status and size");
+    println!("  --source-label <label>       Store this label in the source column of every row");
+    println!("  --explain                    Show how a line (instead of a file) is parsed");
+    println!("  --dry-run-schema             Show the fields populated by the first lines");
+    println!("  --infer                      Infer the types and the JSON keys of the first lines");
+    println!("  --infer-output <file>        Save the inferred configuration to a TOML file");
+    println!("  --regeo <database>           Update the geolocation of the rows of a database");
+    println!("  --mmdb <file>                Geolocate the IPs with this MaxMind database");
+    println!("  --geo-raw                    Store the whole geolocation record of the IPs");
+    println!("  --profile                    Show the time spent in every stage of the import");
+    println!("  --match <regex>              Import only the lines matching the regex");
+    println!("  --exclude <regex>            Skip the lines matching the regex");
+    println!("  --normalize-ua               Store the browser, major version, OS and device");
+    println!("  --ua-browser-only            Detect only the browser of the user agents (faster)");
+    println!("  --cache-backend <memory|disk>");
+    println!("                               Cache of user agents and IPs (disk: less memory)");
+    println!("  --canonical-query            Store the query with the parameters sorted");
+    println!("  --routes                     Store the route of the paths, with the ids replaced");
+    println!(
+        "  --route-patterns <file>      Patterns of the routes, checked before replacing the ids"
+    );
+    println!("  --dedup-consecutive          Skip the lines identical to the previous one");
+    println!(
+        "  --path-prefix <path>         Import only the requests to this path and its subpaths"
+    );
+    println!(
+        "  --min-status <code>          Import only requests with this status code or greater"
+    );
+    println!("  --max-status <code>          Import only requests with this status code or lower");
+    println!("  --only-country <list>        Import only requests from these countries");
+    println!("  --exclude-country <list>     Skip requests from these countries");
+    println!("  --only-continent <list>      Import only requests from these continents");
+    println!("  --exclude-continent <list>   Skip requests from these continents");
+    println!("  --json-events                Print the progress as JSON events to stderr");
+    println!("  --metrics-file <file>        Save Prometheus metrics of the import to a file");
+    println!("  --summary                    Show the counts, status codes and sizes imported");
+    println!("  --summary-json <file>        Save the summary of the import to a JSON file");
+    println!("  --top <column:n,...>         Show the most common values of these columns");
+    println!("  --require-columns <list>     Fail if these columns are mostly null");
+    println!("  --max-null-ratio <ratio>     Maximum nulls of --require-columns (default: 0.5)");
+    println!("  --count-distinct <list>      Show the number of distinct values of these columns");
+    println!("  --size-buckets <bytes,...>   Boundaries of the response sizes in the summary");
+    println!("  --parquet <file>             Export the database to a Parquet file");
+    println!("  --parquet-row-group-size <n> Rows per row group (default: 122880)");
+    println!("  --parquet-codec <codec>      Parquet compression codec (default: snappy)");
+    println!("  --lowercase-path             Lowercase the paths (case-insensitive servers)");
+    println!("  --jobs <n>                   Parse up to n log files at the same time");
+    println!("  --threads <n>                Parse the lines of every log file with n threads");
+    println!("  --follow                     Wait for new lines at the end of the files (tail -F)");
+    println!("  --commit-every <rows>        Save the rows to the database every n rows");
+    println!("  --commit-interval <seconds>  Save the rows to the database every n seconds");
+    println!("  --keep-all                   Save the invalid lines to the database");
+    println!("  --errors-to-db               Save the errors in the parse_errors table instead of a file");
+    println!("  --auto-migrate               Rebuild the database if the schema is incompatible");
+    println!("  --lock-timeout <seconds>     Time to wait if the database is locked (default: 30)");
+    println!("  --max-query-length <n>       Truncate the queries longer than n characters");
+    println!("  --redact-params <list>       Replace the values of these query parameters");
+    println!("  --asset-extensions <list>    Extensions of the static assets (is_asset column)");
+    println!("  --time-bucket <resolution>   Truncate the timestamps to minute, hour or day");
+    println!("  --time-columns               Store the date, hour and weekday of the requests");
+    println!("  --visitor-id <salt>          Store a daily pseudonymous id of the visitors");
+    println!("  --session-cookie <name>      Store the value of this cookie in session_id");
+    println!("  --flatten-query              Save the query parameters to the query_params table");
+    println!("  --incremental-rollups        Update the number of requests by path and day");
+    println!("  --aggregate-only             Save only the number of requests by --group-by");
+    println!("  --group-by <list>            Dimensions of --aggregate-only (default: hour,");
+    println!("                               status_code,path,country)");
+    println!(
+        "  --split-by-day               Save the requests of every day to a different database"
+    );
+    println!("  --csv <file>                 Export the database to a CSV file");
+    println!("  --csv-null <value>           Value of the NULLs in the CSV file (default: empty)");
+    println!("  --arrow <file>               Export the database to an Arrow IPC (Feather) file");
+    println!("  --reverse-dns                Store the hostname of the IPs (slow)");
+    println!("  --dns-timeout <seconds>      Timeout of the reverse DNS lookups (default: 1)");
     println!("");
 }
 
 fn replace_extension(file: &str, new_extension: &str) -> String {
+    let file = file.strip_suffix(".gz").unwrap_or(file);
+
     if file.ends_with(".log") {
         return format!("{}{}", &file[..file.len() - 4], new_extension);
     }
     format!("{}{}", file, new_extension)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order_mark_is_removed() {
+        let filename = env::temp_dir().join(format!("log2duck-bom-{}.log", process::id()));
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 512 "-" "-""#;
+        std::fs::write(&filename, format!("\u{feff}{}\n\u{feff}{}\n", line, line)).unwrap();
+
+        let lines: Vec<String> =
+            read_log_file(filename.to_str().unwrap(), LogFormat::Combined).collect();
+        std::fs::remove_file(&filename).unwrap();
+
+        // Only the mark at the start of the file
+        assert_eq!(lines, [line.to_string(), format!("\u{feff}{}", line)]);
+    }
+}
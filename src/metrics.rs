@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+/** Counters of an import, exported in the Prometheus text format */
+pub struct Metrics<'a> {
+    pub origin: &'a str,
+    pub inserted: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub statuses: &'a BTreeMap<u16, usize>,
+    pub duration: Duration,
+}
+
+impl Metrics<'_> {
+    /** Write the metrics to a file, to be scraped by the node_exporter textfile collector */
+    pub fn write(&self, filename: &str) -> io::Result<()> {
+        let origin = format!("origin=\"{}\"", escape(self.origin));
+        let lines = self.inserted + self.skipped + self.errors;
+        let mut output = String::new();
+
+        counter(&mut output, "lines_total", "Lines read from the log file");
+        output.push_str(&format!("log2duck_lines_total{{{}}} {}\n", origin, lines));
+
+        counter(
+            &mut output,
+            "errors_total",
+            "Lines that couldn't be imported",
+        );
+        output.push_str(&format!(
+            "log2duck_errors_total{{{}}} {}\n",
+            origin, self.errors
+        ));
+
+        counter(&mut output, "skipped_total", "Lines skipped by the filters");
+        output.push_str(&format!(
+            "log2duck_skipped_total{{{}}} {}\n",
+            origin, self.skipped
+        ));
+
+        counter(
+            &mut output,
+            "rows_inserted_total",
+            "Rows inserted in the database",
+        );
+        output.push_str(&format!(
+            "log2duck_rows_inserted_total{{{}}} {}\n",
+            origin, self.inserted
+        ));
+
+        counter(
+            &mut output,
+            "rows_by_status_total",
+            "Rows inserted by status code",
+        );
+        for (status, count) in self.statuses {
+            output.push_str(&format!(
+                "log2duck_rows_by_status_total{{{},status=\"{}\"}} {}\n",
+                origin, status, count
+            ));
+        }
+
+        output.push_str("# HELP log2duck_duration_seconds Duration of the import\n");
+        output.push_str("# TYPE log2duck_duration_seconds gauge\n");
+        output.push_str(&format!(
+            "log2duck_duration_seconds{{{}}} {}\n",
+            origin,
+            self.duration.as_secs_f64()
+        ));
+
+        // Write to a temporary file first so the collector never reads a partial file
+        let tmp = format!("{}.tmp", filename);
+        fs::write(&tmp, output)?;
+        fs::rename(&tmp, filename)
+    }
+}
+
+fn counter(output: &mut String, name: &str, help: &str) {
+    output.push_str(&format!("# HELP log2duck_{} {}\n", name, help));
+    output.push_str(&format!("# TYPE log2duck_{} counter\n", name));
+}
+
+/** Escape a label value */
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
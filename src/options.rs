@@ -0,0 +1,604 @@
+use crate::aggregate::DIMENSIONS;
+use crate::export::PARQUET_CODECS;
+use crate::summary::SIZE_BUCKETS;
+use log2duck::{JsonKeys, LogFormat, LogTemplate, RoutePatterns, TimeBucket};
+use regex::Regex;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+// Number of values shown by --top if the limit is not specified
+const DEFAULT_TOP_LIMIT: usize = 10;
+
+/** A log file, the origin of the site and the label stored in the source column */
+pub struct Source {
+    pub input: String,
+    pub origin: String,
+    pub label: Option<String>,
+}
+
+/** Options passed to the command line */
+#[derive(Default)]
+pub struct Options {
+    pub sources: Vec<Source>,
+    pub manifest: Option<String>,
+    pub output: Option<String>,
+    pub origins: Vec<Url>,
+    pub format: LogFormat,
+    pub log_format: Option<Arc<LogTemplate>>,
+    pub json_keys: Option<JsonKeys>,
+    pub vhost: bool,
+    pub quote: char,
+    pub delimiter: char,
+    pub syslog_strip: bool,
+    pub unescape: bool,
+    pub source_label: Option<String>,
+    pub number_separators: Option<String>,
+    pub profile: bool,
+    pub dry_run_schema: bool,
+    pub infer: bool,
+    pub infer_output: Option<String>,
+    pub explain: bool,
+    pub regeo: Option<String>,
+    pub mmdb: Option<Arc<[u8]>>,
+    pub only_countries: Vec<String>,
+    pub exclude_countries: Vec<String>,
+    pub only_continents: Vec<String>,
+    pub exclude_continents: Vec<String>,
+    pub metrics_file: Option<String>,
+    pub json_events: bool,
+    pub summary: bool,
+    pub summary_json: Option<String>,
+    pub size_buckets: Vec<usize>,
+    pub top: Vec<(String, usize)>,
+    pub count_distinct: Vec<String>,
+    pub require_columns: Vec<String>,
+    pub max_null_ratio: f64,
+    pub reverse_dns: bool,
+    pub dns_timeout: f64,
+    pub parquet: Option<String>,
+    pub parquet_row_group_size: usize,
+    pub parquet_codec: String,
+    pub csv: Option<String>,
+    pub csv_null: String,
+    pub arrow: Option<String>,
+    pub aggregate_only: bool,
+    pub split_by_day: bool,
+    pub incremental_rollups: bool,
+    pub flatten_query: bool,
+    pub group_by: Vec<String>,
+    pub max_query_length: Option<usize>,
+    pub lock_timeout: f64,
+    pub auto_migrate: bool,
+    pub lowercase_path: bool,
+    pub keep_all: bool,
+    pub errors_to_db: bool,
+    pub commit_every: Option<usize>,
+    pub follow: bool,
+    pub commit_interval: Option<Duration>,
+    pub jobs: usize,
+    pub threads: usize,
+    pub visitor_salt: Option<String>,
+    pub session_cookie: Option<String>,
+    pub time_bucket: Option<TimeBucket>,
+    pub time_columns: bool,
+    pub asset_extensions: Option<Vec<String>>,
+    pub redact_params: Vec<String>,
+    pub path_prefix: Option<String>,
+    pub min_status: Option<u16>,
+    pub max_status: Option<u16>,
+    pub dedup_consecutive: bool,
+    pub routes: Option<RoutePatterns>,
+    pub canonical_query: bool,
+    pub normalize_ua: bool,
+    pub ua_browser_only: bool,
+    pub disk_cache: bool,
+    pub geo_raw: bool,
+    pub match_line: Option<Regex>,
+    pub exclude_line: Option<Regex>,
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut positional: Vec<String> = Vec::new();
+        let mut options = Options {
+            quote: '"',
+            delimiter: ' ',
+            dns_timeout: 1.0,
+            lock_timeout: 30.0,
+            jobs: 1,
+            threads: 1,
+            max_null_ratio: 0.5,
+            parquet_row_group_size: 122880,
+            parquet_codec: String::from("snappy"),
+            group_by: list("hour,status_code,path,country"),
+            size_buckets: SIZE_BUCKETS.to_vec(),
+            ..Default::default()
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--manifest" => options.manifest = Some(value(&arg, &mut args)?),
+                "--output" => options.output = Some(value(&arg, &mut args)?),
+                "--origin-file" => options.origins = read_origins(&value(&arg, &mut args)?)?,
+                "--format" => options.format = LogFormat::new(&value(&arg, &mut args)?)?,
+                "--json-keys" => {
+                    let keys = options.json_keys.get_or_insert_with(JsonKeys::default);
+                    for mapping in value(&arg, &mut args)?.split(',') {
+                        let (field, key) = mapping
+                            .split_once('=')
+                            .ok_or_else(|| format!("Invalid value for {}: {}", arg, mapping))?;
+                        keys.set(field.trim(), key.trim())?;
+                    }
+                }
+                "--log-format" => {
+                    let template = LogTemplate::new(&value(&arg, &mut args)?)?;
+                    options.log_format = Some(Arc::new(template));
+                }
+                "--vhost" => options.vhost = true,
+                "--syslog-strip" => options.syslog_strip = true,
+                "--unescape" => options.unescape = true,
+                "--quote" => {
+                    options.quote = match value(&arg, &mut args)?.as_str() {
+                        "double" => '"',
+                        "single" => '\'',
+                        quote => {
+                            return Err(format!("Invalid quote {} (valid: single, double)", quote))
+                        }
+                    }
+                }
+                "--delimiter" => {
+                    options.delimiter = match value(&arg, &mut args)?.as_str() {
+                        "space" => ' ',
+                        "tab" => '\t',
+                        delimiter => {
+                            return Err(format!(
+                                "Invalid delimiter {} (valid: space, tab)",
+                                delimiter
+                            ))
+                        }
+                    }
+                }
+                "--source-label" => options.source_label = Some(value(&arg, &mut args)?),
+                "--number-separators" => {
+                    let separators = value(&arg, &mut args)?;
+                    if separators.contains(|c: char| c.is_ascii_digit()) {
+                        return Err(String::from("--number-separators can't contain digits"));
+                    }
+                    options.number_separators = Some(separators);
+                }
+                "--profile" => options.profile = true,
+                "--dry-run-schema" => options.dry_run_schema = true,
+                "--infer" => options.infer = true,
+                "--infer-output" => options.infer_output = Some(value(&arg, &mut args)?),
+                "--explain" => options.explain = true,
+                "--regeo" => options.regeo = Some(value(&arg, &mut args)?),
+                "--geo-raw" => options.geo_raw = true,
+                "--mmdb" => {
+                    let filename = value(&arg, &mut args)?;
+                    let database = fs::read(&filename).map_err(|err| {
+                        format!("Unable to read the IP database {} ({})", filename, err)
+                    })?;
+                    maxminddb::Reader::from_source(database.as_slice())
+                        .map_err(|err| format!("Invalid IP database {} ({})", filename, err))?;
+                    options.mmdb = Some(database.into());
+                }
+                "--match" => options.match_line = Some(regex(&arg, &mut args)?),
+                "--exclude" => options.exclude_line = Some(regex(&arg, &mut args)?),
+                "--only-country" => options.only_countries = list(&value(&arg, &mut args)?),
+                "--exclude-country" => options.exclude_countries = list(&value(&arg, &mut args)?),
+                "--only-continent" => options.only_continents = list(&value(&arg, &mut args)?),
+                "--exclude-continent" => {
+                    options.exclude_continents = list(&value(&arg, &mut args)?)
+                }
+                "--json-events" => options.json_events = true,
+                "--metrics-file" => options.metrics_file = Some(value(&arg, &mut args)?),
+                "--summary" => options.summary = true,
+                "--summary-json" => options.summary_json = Some(value(&arg, &mut args)?),
+                "--size-buckets" => {
+                    let buckets = list(&value(&arg, &mut args)?)
+                        .iter()
+                        .map(|size| size.parse())
+                        .collect::<Result<Vec<usize>, _>>()
+                        .map_err(|_| format!("Invalid value for {}", arg))?;
+                    if buckets.contains(&0) || buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+                        return Err(String::from(
+                            "--size-buckets must be a list of increasing sizes in bytes",
+                        ));
+                    }
+                    options.size_buckets = buckets;
+                }
+                "--top" => {
+                    for item in list(&value(&arg, &mut args)?) {
+                        let (column, limit) = match item.split_once(':') {
+                            Some((column, limit)) => (
+                                column,
+                                limit
+                                    .parse()
+                                    .map_err(|_| format!("Invalid limit for --top {}", item))?,
+                            ),
+                            None => (item.as_str(), DEFAULT_TOP_LIMIT),
+                        };
+                        options.top.push((column.to_string(), limit));
+                    }
+                }
+                "--count-distinct" => options.count_distinct = list(&value(&arg, &mut args)?),
+                "--require-columns" => options.require_columns = list(&value(&arg, &mut args)?),
+                "--max-null-ratio" => {
+                    let ratio: f64 = number(&arg, &mut args)?;
+                    if !(0.0..=1.0).contains(&ratio) {
+                        return Err(String::from("--max-null-ratio must be between 0 and 1"));
+                    }
+                    options.max_null_ratio = ratio;
+                }
+                "--reverse-dns" => options.reverse_dns = true,
+                "--dns-timeout" => {
+                    let seconds: f64 = number(&arg, &mut args)?;
+                    if !seconds.is_finite() || seconds <= 0.0 {
+                        return Err(String::from("--dns-timeout must be greater than 0"));
+                    }
+                    options.dns_timeout = seconds;
+                }
+                "--flatten-query" => options.flatten_query = true,
+                "--incremental-rollups" => options.incremental_rollups = true,
+                "--aggregate-only" => options.aggregate_only = true,
+                "--group-by" => {
+                    let dimensions = list(&value(&arg, &mut args)?);
+                    if let Some(invalid) = dimensions
+                        .iter()
+                        .find(|dimension| !DIMENSIONS.contains(&dimension.as_str()))
+                    {
+                        return Err(format!(
+                            "Invalid dimension {} (valid: {})",
+                            invalid,
+                            DIMENSIONS.join(", ")
+                        ));
+                    }
+                    if dimensions.is_empty() {
+                        return Err(String::from("Missing value for --group-by"));
+                    }
+                    options.group_by = dimensions;
+                }
+                "--csv" => options.csv = Some(value(&arg, &mut args)?),
+                "--csv-null" => options.csv_null = value(&arg, &mut args)?,
+                "--arrow" => options.arrow = Some(value(&arg, &mut args)?),
+                "--parquet" => options.parquet = Some(value(&arg, &mut args)?),
+                "--parquet-row-group-size" => {
+                    options.parquet_row_group_size = number(&arg, &mut args)?
+                }
+                "--parquet-codec" => {
+                    let codec = value(&arg, &mut args)?.to_lowercase();
+                    if !PARQUET_CODECS.contains(&codec.as_str()) {
+                        return Err(format!(
+                            "Invalid Parquet codec {} (valid: {})",
+                            codec,
+                            PARQUET_CODECS.join(", ")
+                        ));
+                    }
+                    options.parquet_codec = codec;
+                }
+                "--normalize-ua" => options.normalize_ua = true,
+                "--ua-browser-only" => options.ua_browser_only = true,
+                "--cache-backend" => {
+                    options.disk_cache = match value(&arg, &mut args)?.as_str() {
+                        "memory" => false,
+                        "disk" => true,
+                        backend => {
+                            return Err(format!(
+                                "Invalid cache backend {} (valid: memory, disk)",
+                                backend
+                            ))
+                        }
+                    }
+                }
+                "--canonical-query" => options.canonical_query = true,
+                "--routes" => {
+                    options.routes.get_or_insert_with(RoutePatterns::default);
+                }
+                "--route-patterns" => {
+                    let filename = value(&arg, &mut args)?;
+                    let content = fs::read_to_string(&filename).map_err(|err| {
+                        format!("Unable to read the route patterns {} ({})", filename, err)
+                    })?;
+                    options.routes = Some(RoutePatterns::parse(&content));
+                }
+                "--dedup-consecutive" => options.dedup_consecutive = true,
+                "--path-prefix" => options.path_prefix = Some(value(&arg, &mut args)?),
+                "--min-status" => options.min_status = Some(number(&arg, &mut args)?),
+                "--max-status" => options.max_status = Some(number(&arg, &mut args)?),
+                "--redact-params" => options.redact_params = list(&value(&arg, &mut args)?),
+                "--asset-extensions" => {
+                    options.asset_extensions = Some(list(&value(&arg, &mut args)?))
+                }
+                "--time-bucket" => {
+                    options.time_bucket = Some(TimeBucket::new(&value(&arg, &mut args)?)?)
+                }
+                "--split-by-day" => options.split_by_day = true,
+                "--time-columns" => options.time_columns = true,
+                "--visitor-id" => options.visitor_salt = Some(value(&arg, &mut args)?),
+                "--session-cookie" => options.session_cookie = Some(value(&arg, &mut args)?),
+                "--commit-every" => {
+                    let rows: usize = number(&arg, &mut args)?;
+                    if rows == 0 {
+                        return Err(String::from("--commit-every must be greater than 0"));
+                    }
+                    options.commit_every = Some(rows);
+                }
+                "--follow" => options.follow = true,
+                "--commit-interval" => {
+                    let value = value(&arg, &mut args)?;
+                    let seconds: f64 = value
+                        .strip_suffix('s')
+                        .unwrap_or(&value)
+                        .parse()
+                        .map_err(|_| format!("Invalid value for {}", arg))?;
+                    if !seconds.is_finite() || seconds <= 0.0 {
+                        return Err(String::from("--commit-interval must be greater than 0"));
+                    }
+                    options.commit_interval = Some(Duration::from_secs_f64(seconds));
+                }
+                "--jobs" => {
+                    let jobs: usize = number(&arg, &mut args)?;
+                    if jobs == 0 {
+                        return Err(String::from("--jobs must be greater than 0"));
+                    }
+                    options.jobs = jobs;
+                }
+                "--threads" => {
+                    let threads: usize = number(&arg, &mut args)?;
+                    if threads == 0 {
+                        return Err(String::from("--threads must be greater than 0"));
+                    }
+                    options.threads = threads;
+                }
+                "--keep-all" => options.keep_all = true,
+                "--errors-to-db" => options.errors_to_db = true,
+                "--lowercase-path" => options.lowercase_path = true,
+                "--auto-migrate" => options.auto_migrate = true,
+                "--lock-timeout" => {
+                    let seconds: f64 = number(&arg, &mut args)?;
+                    if !seconds.is_finite() || seconds < 0.0 {
+                        return Err(String::from("--lock-timeout must be 0 or greater"));
+                    }
+                    options.lock_timeout = seconds;
+                }
+                "--max-query-length" => options.max_query_length = Some(number(&arg, &mut args)?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => positional.push(arg),
+            }
+        }
+
+        if let (Some(min), Some(max)) = (options.min_status, options.max_status) {
+            if min > max {
+                return Err(String::from(
+                    "--min-status must not be greater than --max-status",
+                ));
+            }
+        }
+
+        if options.json_keys.is_some() && options.format != LogFormat::Json {
+            return Err(String::from(
+                "--json-keys can only be used with --format json",
+            ));
+        }
+
+        // The template replaces the combined format, including the virtual host
+        if options.log_format.is_some() {
+            if options.format != LogFormat::Combined {
+                return Err(String::from("--log-format can't be used with --format"));
+            }
+            if options.vhost {
+                return Err(String::from(
+                    "--log-format can't be used with --vhost (use $host or %v in the format)",
+                ));
+            }
+        }
+
+        if options.jobs > 1 && options.threads > 1 {
+            return Err(String::from("--jobs can't be used with --threads"));
+        }
+
+        // The daily databases only have the log table
+        if options.split_by_day {
+            let incompatible = [
+                ("--aggregate-only", options.aggregate_only),
+                ("--flatten-query", options.flatten_query),
+                ("--incremental-rollups", options.incremental_rollups),
+                ("--keep-all", options.keep_all),
+                ("--errors-to-db", options.errors_to_db),
+                ("--parquet", options.parquet.is_some()),
+                ("--csv", options.csv.is_some()),
+                ("--arrow", options.arrow.is_some()),
+                ("--top", !options.top.is_empty()),
+                ("--count-distinct", !options.count_distinct.is_empty()),
+            ];
+            if let Some((name, _)) = incompatible.iter().find(|(_, used)| *used) {
+                return Err(format!("--split-by-day can't be used with {}", name));
+            }
+        }
+
+        // Only the database is passed to update the geolocation
+        if options.regeo.is_some() {
+            if !positional.is_empty() {
+                return Err(String::from("Unexpected arguments with --regeo"));
+            }
+            return Ok(options);
+        }
+
+        if let Some(manifest) = &options.manifest {
+            if !positional.is_empty() {
+                return Err(String::from("Unexpected arguments with --manifest"));
+            }
+            options.sources = read_manifest(manifest)?;
+        } else {
+            if positional.len() < 2 {
+                return Err(String::from("Expected two arguments: <file> <origin>"));
+            }
+
+            let origin = positional.pop().unwrap();
+            for pattern in positional {
+                for input in expand_glob(&pattern)? {
+                    options.sources.push(Source {
+                        input,
+                        origin: origin.clone(),
+                        label: None,
+                    });
+                }
+            }
+        }
+
+        // The name of the output files can't be derived from stdin
+        let stdin = options.sources.iter().filter(|source| source.input == "-");
+        match stdin.count() {
+            0 => {}
+            1 if options.output.is_some() => {}
+            1 => return Err(String::from("--output is required to read from stdin (-)")),
+            _ => return Err(String::from("stdin (-) can only be read once")),
+        }
+
+        // The files are followed without end, so they must be read at the same time
+        if options.follow {
+            if options.aggregate_only {
+                return Err(String::from("--follow can't be used with --aggregate-only"));
+            }
+            if let Some(source) = options.sources.iter().find(|source| {
+                source.input == "-"
+                    || source.input.starts_with("http://")
+                    || source.input.starts_with("https://")
+            }) {
+                return Err(format!("--follow can't be used with {}", source.input));
+            }
+            if options.sources.len() > options.jobs {
+                return Err(format!(
+                    "--follow needs --jobs {} to read all the files at the same time",
+                    options.sources.len()
+                ));
+            }
+        }
+
+        // The labels of the manifest have preference
+        for source in options.sources.iter_mut() {
+            if source.label.is_none() {
+                source.label = options.source_label.clone();
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/**
+ * Get the files matching a glob pattern (like `'access.log*'`), sorted by name.
+ * The URLs and the paths without wildcards are returned as they are.
+ */
+fn expand_glob(pattern: &str) -> Result<Vec<String>, String> {
+    if pattern.starts_with("http://")
+        || pattern.starts_with("https://")
+        || !pattern.contains(['*', '?', '['])
+    {
+        return Ok(vec![pattern.to_string()]);
+    }
+
+    let paths =
+        glob::glob(pattern).map_err(|err| format!("Invalid pattern {} ({})", pattern, err))?;
+    let files: Vec<String> = paths
+        .filter_map(|path| path.ok())
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+
+    if files.is_empty() {
+        return Err(format!("No log files match {}", pattern));
+    }
+
+    Ok(files)
+}
+
+/**
+ * Read the log files and origins from a manifest file.
+ * Every line contains a file and its origin separated by whitespace or "=",
+ * optionally followed by the source label. Empty lines and lines starting with # are ignored.
+ */
+fn read_manifest(filename: &str) -> Result<Vec<Source>, String> {
+    let content = fs::read_to_string(filename)
+        .map_err(|err| format!("Unable to read the manifest {} ({})", filename, err))?;
+    let mut sources = Vec::new();
+
+    for line in content.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (input, rest) = line
+            .split_once('=')
+            .or_else(|| line.split_once(char::is_whitespace))
+            .ok_or_else(|| format!("Invalid line in the manifest: {}", line))?;
+        let mut rest = rest.split_whitespace();
+        let origin = rest
+            .next()
+            .ok_or_else(|| format!("Invalid line in the manifest: {}", line))?;
+
+        sources.push(Source {
+            input: input.trim().to_string(),
+            origin: origin.to_string(),
+            label: rest.next().map(|label| label.to_string()),
+        });
+    }
+
+    if sources.is_empty() {
+        return Err(format!("The manifest {} is empty", filename));
+    }
+
+    Ok(sources)
+}
+
+/**
+ * Read the origins accepted in addition to the origin of the logs, one per line.
+ * Empty lines and lines starting with # are ignored.
+ */
+fn read_origins(filename: &str) -> Result<Vec<Url>, String> {
+    let content = fs::read_to_string(filename)
+        .map_err(|err| format!("Unable to read the origins {} ({})", filename, err))?;
+
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Url::parse(line)
+                .ok()
+                .filter(|url| url.host_str().is_some())
+                .ok_or_else(|| format!("Invalid origin in {}: {}", filename, line))
+        })
+        .collect()
+}
+
+/** Get the value of an option */
+fn value(name: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", name))
+}
+
+/** Get the numeric value of an option */
+fn number<T: FromStr>(name: &str, args: &mut impl Iterator<Item = String>) -> Result<T, String> {
+    value(name, args)?
+        .parse()
+        .map_err(|_| format!("Invalid value for {}", name))
+}
+
+/** Get the regular expression value of an option */
+fn regex(name: &str, args: &mut impl Iterator<Item = String>) -> Result<Regex, String> {
+    Regex::new(&value(name, args)?)
+        .map_err(|err| format!("Invalid regular expression for {} ({})", name, err))
+}
+
+/** Split a comma-separated list of values */
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
@@ -0,0 +1,411 @@
+use crate::db::Cursor;
+use crate::options::{Options, Source};
+use crate::{parse_config, read_log_file, FollowedFile};
+use log2duck::{
+    mark_duplicates, LogEntry, LogError, LookupCache, ParseConfig, ParserServices, Profile,
+};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{iter, thread};
+
+// Number of entries parsed by the workers waiting to be appended to the database
+const CHANNEL_SIZE: usize = 10_000;
+
+// Number of lines sent together to the workers of --threads
+const CHUNK_SIZE: usize = 1000;
+
+/** A line of a log file, with the result of parsing it */
+pub struct Parsed<'o> {
+    pub source: &'o Source,
+    pub number: usize,
+    pub result: Result<LogEntry, LogError>,
+}
+
+/**
+ * Parse the log files one after another in the current thread.
+ * Returns the time spent reading and parsing the lines.
+ */
+pub fn serial<'o>(
+    options: &'o Options,
+    cursors: &[Cursor],
+    cache: Arc<dyn LookupCache>,
+    mut consume: impl FnMut(Parsed<'o>),
+) -> Profile {
+    let mut services = services(options);
+    services.set_shared_cache(cache);
+    let mut profile = Profile::default();
+
+    for source in &options.sources {
+        parse_source(
+            options,
+            source,
+            cursors,
+            &mut services,
+            &mut profile,
+            &mut |parsed| {
+                consume(parsed);
+                true
+            },
+        );
+    }
+
+    add_services_profile(&mut profile, &services);
+    profile
+}
+
+/**
+ * Parse several log files at the same time, with a worker thread per file (up to `jobs`).
+ * The workers share the cache of user agents and IPs. The entries are sent through
+ * a bounded channel to the current thread, that consumes them,
+ * so only one thread writes to the database. The lines of a file keep their order,
+ * but the lines of different files are interleaved.
+ * Returns the time spent reading and parsing the lines, summed across the workers.
+ */
+pub fn parallel<'o>(
+    options: &'o Options,
+    cursors: &[Cursor],
+    jobs: usize,
+    cache: Arc<dyn LookupCache>,
+    mut consume: impl FnMut(Parsed<'o>),
+) -> Profile {
+    let next_source = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(options.sources.len()))
+            .map(|_| {
+                let sender = sender.clone();
+                let next_source = &next_source;
+                let cache = cache.clone();
+
+                scope.spawn(move || {
+                    let mut services = services(options);
+                    services.set_shared_cache(cache);
+                    let mut profile = Profile::default();
+
+                    while let Some(source) = options
+                        .sources
+                        .get(next_source.fetch_add(1, Ordering::Relaxed))
+                    {
+                        parse_source(
+                            options,
+                            source,
+                            cursors,
+                            &mut services,
+                            &mut profile,
+                            &mut |parsed| sender.send(parsed).is_ok(),
+                        );
+                    }
+
+                    add_services_profile(&mut profile, &services);
+                    profile
+                })
+            })
+            .collect();
+
+        // The channel is closed when all workers finish
+        drop(sender);
+        for parsed in receiver {
+            consume(parsed);
+        }
+
+        let mut profile = Profile::default();
+        for worker in workers {
+            let worker = worker.join().unwrap();
+            profile.reading += worker.reading;
+            profile.parsing += worker.parsing;
+            profile.user_agent += worker.user_agent;
+            profile.geolocation += worker.geolocation;
+        }
+        profile
+    })
+}
+
+/**
+ * Lines read by the reader thread of `threaded`, with the index of the source and the config
+ * to parse them (that changes after the `#Fields` headers of the CloudFront logs).
+ * Every line has its number and whether it's a consecutive duplicate.
+ */
+struct Chunk {
+    sequence: usize,
+    source: usize,
+    config: Arc<ParseConfig>,
+    lines: Vec<(usize, String, bool)>,
+}
+
+/**
+ * Parse the log files one after another, splitting the lines between several worker threads.
+ * A reader thread sends the lines in chunks to the workers, that parse them with their own
+ * services sharing a cache of user agents and IPs (so every value is looked up only once),
+ * and the current thread consumes the entries in the order of the lines.
+ * Returns the time spent reading and parsing the lines, summed across the workers.
+ */
+pub fn threaded<'o>(
+    options: &'o Options,
+    cursors: &[Cursor],
+    threads: usize,
+    cache: Arc<dyn LookupCache>,
+    mut consume: impl FnMut(Parsed<'o>),
+) -> Profile {
+    let configs: Vec<ParseConfig> = options
+        .sources
+        .iter()
+        .map(|source| parse_config(options, cursors, source))
+        .collect();
+    let (line_sender, line_receiver) = mpsc::sync_channel::<Chunk>(threads * 2);
+    let line_receiver = Arc::new(Mutex::new(line_receiver));
+    let (sender, receiver) = mpsc::sync_channel(threads * 2);
+
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || read_chunks(options, configs, line_sender));
+
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let sender = sender.clone();
+                let line_receiver = line_receiver.clone();
+                let cache = cache.clone();
+
+                scope.spawn(move || {
+                    let mut services = services(options);
+                    services.set_shared_cache(cache);
+                    let mut profile = Profile::default();
+
+                    // The lock is released after receiving the chunk
+                    while let Ok(chunk) = line_receiver.lock().unwrap().recv() {
+                        let source = &options.sources[chunk.source];
+                        let time = Instant::now();
+                        let parsed: Vec<Parsed> = chunk
+                            .lines
+                            .into_iter()
+                            .map(|(number, line, duplicated)| Parsed {
+                                source,
+                                number,
+                                result: parse_line(line, duplicated, &mut services, &chunk.config),
+                            })
+                            .collect();
+                        profile.parsing += time.elapsed();
+
+                        if sender.send((chunk.sequence, parsed)).is_err() {
+                            break;
+                        }
+                    }
+
+                    add_services_profile(&mut profile, &services);
+                    profile
+                })
+            })
+            .collect();
+
+        // The channels are closed when the reader and the workers finish
+        drop(sender);
+        drop(line_receiver);
+
+        // The chunks can be parsed in any order, so they are kept until the previous ones arrive
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (sequence, parsed) in receiver {
+            pending.insert(sequence, parsed);
+            while let Some(parsed) = pending.remove(&next) {
+                parsed.into_iter().for_each(&mut consume);
+                next += 1;
+            }
+        }
+
+        let mut profile = Profile {
+            reading: reader.join().unwrap(),
+            ..Default::default()
+        };
+        for worker in workers {
+            let worker = worker.join().unwrap();
+            profile.parsing += worker.parsing;
+            profile.user_agent += worker.user_agent;
+            profile.geolocation += worker.geolocation;
+        }
+        profile
+    })
+}
+
+/**
+ * Read the lines of the log files and send them in chunks to the workers of `threaded`.
+ * Returns the time spent reading the lines.
+ */
+fn read_chunks(
+    options: &Options,
+    configs: Vec<ParseConfig>,
+    sender: mpsc::SyncSender<Chunk>,
+) -> Duration {
+    let mut reading = Duration::ZERO;
+    let mut sequence = 0;
+
+    // With --follow, the lines are sent as they arrive
+    let chunk_size = if options.follow { 1 } else { CHUNK_SIZE };
+
+    for ((index, source), config) in options.sources.iter().enumerate().zip(configs) {
+        if options.sources.len() > 1 {
+            println!("Reading {}...", source.input);
+        }
+
+        let mut config = Arc::new(config);
+        let mut lines = mark_duplicates(read_source(options, source), options.dedup_consecutive);
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut send = |lines: Vec<_>, config: &Arc<ParseConfig>| {
+            let chunk = Chunk {
+                sequence,
+                source: index,
+                config: config.clone(),
+                lines,
+            };
+            sequence += 1;
+            sender.send(chunk).is_ok()
+        };
+
+        loop {
+            let time = Instant::now();
+            let line = lines.next();
+            reading += time.elapsed();
+            let end = line.is_none();
+
+            // The lines after a #Fields header of the CloudFront logs go in a new chunk
+            // with the fields of the header
+            let header = line
+                .as_ref()
+                .and_then(|(_, line, _)| config.after_header(line));
+            if let Some(header) = header {
+                if !chunk.is_empty() && !send(std::mem::take(&mut chunk), &config) {
+                    return reading;
+                }
+                config = Arc::new(header);
+            }
+
+            if let Some(line) = line {
+                chunk.push(line);
+            }
+
+            // Send the chunk when it's full or at the end of the file
+            if chunk.len() >= chunk_size || (end && !chunk.is_empty()) {
+                let lines = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+                if !send(lines, &config) {
+                    return reading;
+                }
+            }
+
+            if end {
+                break;
+            }
+        }
+    }
+
+    reading
+}
+
+/** Services to parse the entries, configured with the options */
+pub fn services(options: &Options) -> ParserServices<'static> {
+    let mut services = ParserServices::new();
+
+    if let Some(database) = &options.mmdb {
+        services.set_shared_ip_database(database.clone()).unwrap();
+    }
+
+    if options.profile {
+        services.enable_profile();
+    }
+
+    if options.reverse_dns {
+        services.enable_reverse_dns(Duration::from_secs_f64(options.dns_timeout));
+    }
+
+    if options.geo_raw {
+        services.enable_geo_raw();
+    }
+
+    if options.ua_browser_only {
+        services.enable_browser_only();
+    }
+
+    services
+}
+
+fn add_services_profile(profile: &mut Profile, services: &ParserServices) {
+    if let Some(services_profile) = services.profile() {
+        profile.user_agent += services_profile.user_agent;
+        profile.geolocation += services_profile.geolocation;
+    }
+}
+
+/**
+ * Read and parse the lines of a log file, skipping the old logs.
+ * Stops if `send` returns false (the consumer is gone).
+ */
+fn parse_source<'o>(
+    options: &'o Options,
+    source: &'o Source,
+    cursors: &[Cursor],
+    services: &mut ParserServices,
+    profile: &mut Profile,
+    send: &mut dyn FnMut(Parsed<'o>) -> bool,
+) {
+    if options.sources.len() > 1 {
+        println!("Reading {}...", source.input);
+    }
+
+    let mut config = parse_config(options, cursors, source);
+    let mut lines = mark_duplicates(read_source(options, source), options.dedup_consecutive);
+
+    loop {
+        let time = Instant::now();
+        let (number, line, duplicated) = match lines.next() {
+            Some(line) => line,
+            None => break,
+        };
+        profile.reading += time.elapsed();
+
+        // The #Fields header of the CloudFront logs sets the fields of the next lines
+        if let Some(header) = config.after_header(&line) {
+            config = header;
+        }
+
+        let time = Instant::now();
+        let result = parse_line(line, duplicated, services, &config);
+        profile.parsing += time.elapsed();
+
+        if !send(Parsed {
+            source,
+            number,
+            result,
+        }) {
+            break;
+        }
+    }
+}
+
+/** Parse a line, skipping the lines logged twice (marked by `mark_duplicates`) */
+fn parse_line(
+    line: String,
+    duplicated: bool,
+    services: &mut ParserServices,
+    config: &ParseConfig,
+) -> Result<LogEntry, LogError> {
+    if duplicated {
+        Err(LogError::new_filtered(&line))
+    } else {
+        LogEntry::parse(line, services, config)
+    }
+}
+
+/** Read the lines of a log file, waiting for the new lines with --follow */
+fn read_source(options: &Options, source: &Source) -> Box<dyn Iterator<Item = String>> {
+    if options.follow {
+        match FollowedFile::open(&source.input) {
+            Ok(file) => Box::new(file),
+            // The error is reported and the other sources are still imported
+            Err(e) => {
+                println!("Error: Unable to read {} ({})", source.input, e);
+                Box::new(iter::empty())
+            }
+        }
+    } else {
+        Box::new(read_log_file(&source.input, options.format))
+    }
+}
@@ -0,0 +1,173 @@
+use crate::db::{self, Cursor, Import, LastImported};
+use crate::export::quote;
+use crate::options::Source;
+use chrono::NaiveDate;
+use duckdb::Connection;
+use log2duck::LogEntry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+// Maximum number of daily databases attached at the same time, the least recently used is detached
+const MAX_ATTACHED_DAYS: usize = 8;
+
+/**
+ * The databases of every day imported with --split-by-day (`access-2024-01-05.db`).
+ * The entries are appended to a staging table and moved to the database of their day when saved.
+ * Every database has its own cursors, so a day is imported incrementally like a single database.
+ */
+pub struct DailyDatabases<'a> {
+    name: &'a str,
+    lock_timeout: Duration,
+    auto_migrate: bool,
+    vhost: bool,
+    cursors: HashMap<NaiveDate, Vec<Cursor>>,
+    last_imported: HashMap<NaiveDate, LastImported>,
+    staged: BTreeSet<NaiveDate>,
+    attached: Vec<NaiveDate>,
+    inserted: BTreeMap<NaiveDate, usize>,
+}
+
+impl<'a> DailyDatabases<'a> {
+    pub fn new(
+        name: &'a str,
+        lock_timeout: Duration,
+        auto_migrate: bool,
+        vhost: bool,
+    ) -> DailyDatabases<'a> {
+        DailyDatabases {
+            name,
+            lock_timeout,
+            auto_migrate,
+            vhost,
+            cursors: HashMap::new(),
+            last_imported: HashMap::new(),
+            staged: BTreeSet::new(),
+            attached: Vec::new(),
+            inserted: BTreeMap::new(),
+        }
+    }
+
+    /** Number of days with new entries */
+    pub fn days(&self) -> usize {
+        self.inserted.len()
+    }
+
+    /**
+     * Check whether the entry is newer than the cursor of its source (and virtual host)
+     * in the database of its day. The database is created the first time a day is found.
+     */
+    pub fn accepts(&mut self, source: &Source, log: &LogEntry) -> Result<bool, String> {
+        let day = log.timestamp.date_naive();
+
+        if !self.cursors.contains_key(&day) {
+            let conn = db::open(&self.filename(day), self.lock_timeout)?;
+            db::setup(&conn, self.auto_migrate)?;
+            self.cursors.insert(day, db::cursors(&conn)?);
+        }
+
+        let cursor = self.cursors[&day]
+            .iter()
+            .filter(|cursor| cursor.source == source.label)
+            .filter(|cursor| !self.vhost || cursor.vhost == log.vhost)
+            .map(|cursor| cursor.timestamp)
+            .max()
+            .unwrap_or(0);
+
+        let timestamp = log.exact_timestamp.timestamp_micros();
+        if timestamp <= cursor {
+            return Ok(false);
+        }
+
+        let last = self
+            .last_imported
+            .entry(day)
+            .or_default()
+            .entry((source.label.clone(), log.vhost.clone()))
+            .or_insert(0);
+        *last = (*last).max(timestamp);
+
+        self.staged.insert(day);
+        Ok(true)
+    }
+
+    /**
+     * Move the rows of the staging log table to the databases of their days.
+     * The appender of the staging table must be flushed before.
+     */
+    pub fn save(&mut self, conn: &Connection) -> Result<(), String> {
+        for day in std::mem::take(&mut self.staged) {
+            let alias = self.attach(conn, day)?;
+            let rows = conn
+                .execute(
+                    &format!(
+                        "INSERT INTO {}.log SELECT * FROM log WHERE CAST(timestamp AS DATE) = DATE '{}'",
+                        alias, day
+                    ),
+                    [],
+                )
+                .map_err(|err| err.to_string())?;
+            *self.inserted.entry(day).or_insert(0) += rows;
+        }
+
+        conn.execute_batch("DELETE FROM log")
+            .map_err(|err| err.to_string())
+    }
+
+    /** Detach the databases and save the import and the cursors in the meta tables of every day */
+    pub fn finish(mut self, conn: &Connection, import: &Import) -> Result<(), String> {
+        for day in std::mem::take(&mut self.attached) {
+            detach(conn, day)?;
+        }
+
+        for (day, inserted) in &self.inserted {
+            let conn = db::open(&self.filename(*day), self.lock_timeout)?;
+            let import = Import {
+                inserted: *inserted,
+                ..*import
+            };
+            db::save_import(&conn, &import)?;
+            if let Some(cursors) = self.last_imported.get(day) {
+                db::save_cursors(&conn, cursors)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn filename(&self, day: NaiveDate) -> String {
+        format!("{}-{}.db", self.name, day)
+    }
+
+    /** Attach the database of a day (if it's not attached yet) and return its alias */
+    fn attach(&mut self, conn: &Connection, day: NaiveDate) -> Result<String, String> {
+        match self.attached.iter().position(|attached| *attached == day) {
+            Some(index) => {
+                self.attached.remove(index);
+            }
+            None => {
+                if self.attached.len() >= MAX_ATTACHED_DAYS {
+                    detach(conn, self.attached.remove(0))?;
+                }
+                conn.execute_batch(&format!(
+                    "ATTACH {} AS {}",
+                    quote(&self.filename(day)),
+                    alias(day)
+                ))
+                .map_err(|err| err.to_string())?;
+            }
+        }
+
+        self.attached.push(day);
+        Ok(alias(day))
+    }
+}
+
+fn detach(conn: &Connection, day: NaiveDate) -> Result<(), String> {
+    conn.execute_batch(&format!("DETACH {}", alias(day)))
+        .map_err(|err| err.to_string())
+}
+
+/** Name of the database of a day in the staging connection: day_20240105 */
+fn alias(day: NaiveDate) -> String {
+    format!("day_{}", day.format("%Y%m%d"))
+}
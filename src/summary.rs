@@ -0,0 +1,260 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+// Maximum number of characters of the sparkline, the hours are merged if there are more
+const SPARKLINE_WIDTH: usize = 120;
+
+/** Default boundaries of the size histogram: 1KB, 10KB, 100KB and 1MB */
+pub const SIZE_BUCKETS: [usize; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+/** Most common values of a column (requested with --top) and their number of requests */
+pub type TopValues = Vec<(Option<String>, u64)>;
+
+/** Number of responses and bytes served by size, accumulated while the entries are parsed */
+pub struct SizeHistogram {
+    boundaries: Vec<usize>,
+    counts: Vec<usize>,
+    bytes: u64,
+}
+
+impl SizeHistogram {
+    /** The boundaries must be sorted, a response is in the first bucket with a greater boundary */
+    pub fn new(boundaries: Vec<usize>) -> SizeHistogram {
+        SizeHistogram {
+            counts: vec![0; boundaries.len() + 1],
+            boundaries,
+            bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, size: usize) {
+        let bucket = self
+            .boundaries
+            .partition_point(|boundary| *boundary <= size);
+        self.counts[bucket] += 1;
+        self.bytes += size as u64;
+    }
+
+    pub fn average(&self) -> f64 {
+        let responses: usize = self.counts.iter().sum();
+        if responses == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / responses as f64
+    }
+
+    /** Label and number of responses of every bucket: <1KB, 1KB-10KB, ..., >=1MB */
+    pub fn buckets(&self) -> Vec<(String, usize)> {
+        let last = self.boundaries.len();
+
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(index, count)| {
+                let label = match index {
+                    0 => format!("<{}", size(self.boundaries.first().copied().unwrap_or(0))),
+                    index if index == last => format!(">={}", size(self.boundaries[index - 1])),
+                    index => format!(
+                        "{}-{}",
+                        size(self.boundaries[index - 1]),
+                        size(self.boundaries[index])
+                    ),
+                };
+                (label, *count)
+            })
+            .collect()
+    }
+}
+
+/** Overview of an import, printed with --summary or saved with --summary-json */
+pub struct Summary<'a> {
+    pub origin: &'a str,
+    pub inserted: usize,
+    pub skipped: usize,
+    pub errors: usize,
+    pub statuses: &'a BTreeMap<u16, usize>,
+    pub sizes: &'a SizeHistogram,
+    pub tops: &'a [(String, TopValues)],
+    pub distinct: &'a [(String, u64)],
+    pub hourly: &'a [(String, u64)],
+    pub duration: Duration,
+}
+
+impl Summary<'_> {
+    pub fn print(&self) {
+        println!("Summary:");
+        println!(
+            "  Lines read:     {:>12}",
+            self.inserted + self.skipped + self.errors
+        );
+        println!("  Rows inserted:  {:>12}", self.inserted);
+        println!("  Skipped:        {:>12}", self.skipped);
+        println!("  Errors:         {:>12}", self.errors);
+        println!("  Bytes served:   {:>12}", self.sizes.bytes);
+        println!("  Average size:   {:>12.0}", self.sizes.average());
+        println!("  Status codes:");
+        for (status, count) in self.statuses {
+            println!("    {:<14}{:>12}", status, count);
+        }
+        println!("  Response sizes:");
+        for (label, count) in self.sizes.buckets() {
+            println!("    {:<14}{:>12}", label, count);
+        }
+        if let (Some((from, _)), Some((to, _))) = (self.hourly.first(), self.hourly.last()) {
+            let counts: Vec<u64> = self.hourly.iter().map(|(_, count)| *count).collect();
+            let max = counts.iter().max().unwrap_or(&0);
+            println!("  Requests by hour (from {} to {}, max {}):", from, to, max);
+            println!("    {}", sparkline(&counts));
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let statuses: serde_json::Map<String, Value> = self
+            .statuses
+            .iter()
+            .map(|(status, count)| (status.to_string(), json!(count)))
+            .collect();
+        let sizes: Vec<Value> = self
+            .sizes
+            .buckets()
+            .into_iter()
+            .map(|(label, count)| json!({ "bucket": label, "count": count }))
+            .collect();
+        let tops: serde_json::Map<String, Value> = self
+            .tops
+            .iter()
+            .map(|(column, rows)| {
+                let rows: Vec<Value> = rows
+                    .iter()
+                    .map(|(value, count)| json!({ "value": value, "count": count }))
+                    .collect();
+                (column.clone(), Value::from(rows))
+            })
+            .collect();
+
+        json!({
+            "origin": self.origin,
+            "lines": self.inserted + self.skipped + self.errors,
+            "inserted": self.inserted,
+            "skipped": self.skipped,
+            "errors": self.errors,
+            "statuses": statuses,
+            "bytes": self.sizes.bytes,
+            "average_size": self.sizes.average(),
+            "sizes": sizes,
+            "top": tops,
+            "distinct": self
+                .distinct
+                .iter()
+                .map(|(column, count)| (column.clone(), json!(count)))
+                .collect::<serde_json::Map<String, Value>>(),
+            "hourly": self
+                .hourly
+                .iter()
+                .map(|(hour, count)| json!({ "hour": hour, "requests": count }))
+                .collect::<Vec<Value>>(),
+            "duration_seconds": self.duration.as_secs_f64(),
+        })
+    }
+
+    pub fn write_json(&self, filename: &str) -> io::Result<()> {
+        fs::write(
+            filename,
+            serde_json::to_string_pretty(&self.to_json()).unwrap(),
+        )
+    }
+}
+
+/** Print the most common values of a column, with the number of requests */
+pub fn print_top(column: &str, rows: &[(Option<String>, u64)]) {
+    let width = rows
+        .iter()
+        .map(|(value, _)| value.as_deref().unwrap_or("(null)").chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp(column.len(), 60);
+
+    println!("Top {}:", column);
+    for (value, count) in rows {
+        let value: String = value
+            .as_deref()
+            .unwrap_or("(null)")
+            .chars()
+            .take(60)
+            .collect();
+        println!("  {:<width$}  {:>12}", value, count, width = width);
+    }
+    if rows.is_empty() {
+        println!("  (no requests)");
+    }
+}
+
+/** Print the approximate number of distinct values of some columns */
+pub fn print_distinct(columns: &[(String, u64)]) {
+    let width = columns
+        .iter()
+        .map(|(column, _)| column.len())
+        .max()
+        .unwrap_or(0);
+
+    println!("Distinct values (approximate):");
+    for (column, count) in columns {
+        println!("  {:<width$}  {:>12}", column, count, width = width);
+    }
+}
+
+/**
+ * Draw the counts with block characters, or ASCII characters if the terminal is not UTF-8.
+ * The empty hours are blank, so the gaps are visible.
+ */
+fn sparkline(counts: &[u64]) -> String {
+    let levels: Vec<char> = if unicode_terminal() {
+        "▁▂▃▄▅▆▇█".chars().collect()
+    } else {
+        "_.-=+*#@".chars().collect()
+    };
+
+    // Merge the hours to fit in the width
+    let chunk = counts.len().div_ceil(SPARKLINE_WIDTH).max(1);
+    let counts: Vec<u64> = counts
+        .chunks(chunk)
+        .map(|hours| hours.iter().sum())
+        .collect();
+    let max = counts.iter().copied().max().unwrap_or(0);
+
+    counts
+        .iter()
+        .map(|count| match count {
+            0 => ' ',
+            count => {
+                let level = (count * levels.len() as u64).div_ceil(max) as usize;
+                levels[level.clamp(1, levels.len()) - 1]
+            }
+        })
+        .collect()
+}
+
+/** Check the locale variables to know whether the terminal supports UTF-8 */
+fn unicode_terminal() -> bool {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
+        .is_some_and(|locale| {
+            let locale = locale.to_uppercase();
+            locale.contains("UTF-8") || locale.contains("UTF8")
+        })
+}
+
+/** Short representation of a size: 1000 -> 1KB, 1500 -> 1500B */
+fn size(bytes: usize) -> String {
+    match bytes {
+        0 => String::from("0B"),
+        bytes if bytes % 1_000_000 == 0 => format!("{}MB", bytes / 1_000_000),
+        bytes if bytes % 1_000 == 0 => format!("{}KB", bytes / 1_000),
+        bytes => format!("{}B", bytes),
+    }
+}